@@ -0,0 +1,26 @@
+// table driven vs bitwise eBUS CRC8, run with `cargo bench --bench crc8`
+use std::hint::black_box;
+use std::time::Instant;
+
+use ebus_mqtt::ebus::crc8::{update_crc, update_crc_bitwise};
+
+const FRAME_BYTES: usize = 1 << 20;
+const ROUNDS: u32 = 20;
+
+fn bench(name: &str, update: fn(u8, u8) -> u8, data: &[u8]) {
+    let start = Instant::now();
+    let mut crc = 0;
+    for _ in 0..ROUNDS {
+        crc = data.iter().fold(crc, |crc, b| update(crc, *b));
+    }
+    black_box(crc);
+    let elapsed = start.elapsed();
+    let per_byte = elapsed.as_nanos() as f64 / (data.len() as f64 * ROUNDS as f64);
+    println!("{:<8} {:>8.3} ns/byte ({:?} for {} MiB)", name, per_byte, elapsed, (data.len() * ROUNDS as usize) >> 20);
+}
+
+fn main() {
+    let data: Vec<u8> = (0..FRAME_BYTES).map(|i| (i * 31 + 7) as u8).collect();
+    bench("table", update_crc, black_box(&data));
+    bench("bitwise", update_crc_bitwise, black_box(&data));
+}
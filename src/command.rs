@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::ebus::sender::{EbusSender, SendError};
+use crate::mapper;
+use crate::mqtt::{Incoming, MqttPublisher};
+
+// how often queued bus events are dropped while no command is sent
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+
+// bus writes requested over MQTT: payload on <message topic>/set of a writable
+// message is encoded into its request telegram and sent, outcome is published
+// to <message topic>/result as { "result": "ack" | "nack" | "error", "error": ... }
+pub struct Commands {
+    // command topic -> message definition
+    messages: HashMap<String, serde_json::Value>,
+    // our master address
+    source: u8,
+}
+
+impl Commands {
+    pub fn new(messages: HashMap<String, serde_json::Value>, source: u8) -> Commands {
+        Commands { messages, source }
+    }
+
+    pub fn topics(&self) -> Vec<String> {
+        self.messages.keys().cloned().collect()
+    }
+
+    // handles commands until the MQTT side is gone, sender is shared with
+    // receiver replacing its writer after reconnect
+    pub fn run(&self, incoming: Receiver<Incoming>, sender: Arc<Mutex<EbusSender>>, publisher: &MqttPublisher) {
+        loop {
+            match incoming.recv_timeout(IDLE_INTERVAL) {
+                Ok((topic, payload)) => {
+                    let Some(msg) = self.messages.get(&topic) else {
+                        continue;
+                    };
+                    let result = self.execute(msg, &payload, &mut sender.lock().unwrap());
+                    let answer = match &result {
+                        Ok(()) => serde_json::json!({ "result": "ack" }),
+                        Err(CommandError::Send(SendError::Nack)) => serde_json::json!({ "result": "nack" }),
+                        Err(e) => serde_json::json!({ "result": "error", "error": e.to_string() }),
+                    };
+                    match &result {
+                        Ok(()) => info!("Command {} sent", topic),
+                        Err(e) => warn!("Command {} failed: {}", topic, e),
+                    }
+                    let result_topic = format!("{}/result", topic.strip_suffix("/set").unwrap_or(&topic));
+                    publisher.publish(&result_topic, answer.to_string());
+                }
+                Err(RecvTimeoutError::Timeout) => sender.lock().unwrap().discard_events(),
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    fn execute(&self, msg: &serde_json::Value, payload: &[u8], sender: &mut EbusSender) -> Result<(), CommandError> {
+        // bare text which is no json (eg. value name) is taken as string
+        let text = String::from_utf8_lossy(payload);
+        let value = serde_json::from_str(&text).unwrap_or_else(|_| serde_json::Value::String(text.trim().to_string()));
+        let (dest, pbsb, data) = mapper::encode_request(msg, &value).map_err(CommandError::Encode)?;
+        sender.send_request(self.source, dest, pbsb, &data).map_err(CommandError::Send)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum CommandError {
+    // payload doesn't fit message definition
+    Encode(String),
+    Send(SendError),
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CommandError::Encode(e) => write!(f, "{}", e),
+            CommandError::Send(e) => write!(f, "{}", e),
+        }
+    }
+}
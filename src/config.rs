@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::BufReader;
+
+use log::{warn, LevelFilter};
+use serde::Deserialize;
+
+use crate::ebus::{address, sender};
+use crate::ebus::capture::{CaptureLog, DEFAULT_CAPTURE_MAX_SIZE};
+use crate::ebus::parser::{CrcMode, EbusParserBuilder, EbusProtocol, DEFAULT_BUFFER_LIMIT, MAX_DATA_LEN};
+use crate::mapper::OutputMode;
+use crate::source::{EbusSource, DEFAULT_SERIAL_BAUD};
+
+
+// config.json contents
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub ebus: EbusConfig,
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    pub include_units: bool,
+    // add "ts", "response_latency_ms" and "frame_gap_ms" to published values
+    #[serde(default)]
+    pub include_timing: bool,
+    // add "acked" (destination ACKed request) to published values
+    #[serde(default)]
+    pub include_acked: bool,
+    // add <field>_raw, the integer before factor / offset, to numeric values
+    #[serde(default)]
+    pub include_raw: bool,
+    // group output by circuit and message, one MQTT topic per field
+    #[serde(default)]
+    pub nested_output: bool,
+    // telegrams matching no definition, logged at info level and / or
+    // published raw to unmatched_topic (eg. "{base}/unmatched")
+    #[serde(default)]
+    pub log_unmatched: bool,
+    pub unmatched_topic: Option<String>,
+    // stdout output of telegrams: text, jsonl or none
+    #[serde(default)]
+    pub output: OutputMode,
+    #[serde(default)]
+    pub log_level: LogLevel,
+    // publish fields only when their value changes, but at least every
+    // publish_max_age seconds when given
+    #[serde(default)]
+    pub publish_on_change: bool,
+    pub publish_max_age: Option<u64>,
+    // publish each message topic at most once per interval, newest values win
+    pub min_publish_interval_ms: Option<u64>,
+    // publish time each message was last seen to <message topic>/last_seen
+    #[serde(default)]
+    pub publish_last_seen: bool,
+    // decimal places published float values are rounded to (fields may set "decimals")
+    pub decimals: Option<u32>,
+    // publish Home Assistant MQTT discovery config at startup
+    #[serde(default)]
+    pub ha_discovery: bool,
+    // raw frame capture log, rotated when reaching capture_max_size bytes
+    pub capture_file: Option<String>,
+    #[serde(default = "default_capture_max_size")]
+    pub capture_max_size: u64,
+    // friendly names of bus addresses, hex address ("10" or "0x10") -> name
+    #[serde(default)]
+    pub addresses: HashMap<String, String>,
+    // Prometheus metrics endpoint
+    pub metrics: Option<MetricsConfig>,
+    // circuit names to decode (all when absent) / to skip
+    pub enabled_circuits: Option<Vec<String>>,
+    #[serde(default)]
+    pub disabled_circuits: Vec<String>,
+    // last decoded values, republished retained at startup
+    pub state_file: Option<String>,
+    // hex addresses telegrams have to come from / go to, others are dropped
+    // before decoding (all when absent)
+    pub only_src: Option<Vec<String>>,
+    pub only_dest: Option<Vec<String>>,
+    // definition files of old configs, moved to ebus.definitions by Config::load
+    load: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Debug,
+    #[default]
+    Info,
+    Warning,
+    Error,
+}
+
+impl LogLevel {
+    pub fn filter(&self) -> LevelFilter {
+        match self {
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Warning => LevelFilter::Warn,
+            LogLevel::Error => LevelFilter::Error
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EbusType {
+    Tcp,
+    Serial,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EbusConfig {
+    #[serde(default = "default_ebus_host")]
+    pub host: String,
+    #[serde(default = "default_ebus_port")]
+    pub port: u16,
+    #[serde(rename = "type", default = "default_ebus_type")]
+    pub kind: EbusType,
+    // interface stream format, enhanced or raw
+    #[serde(default)]
+    pub protocol: EbusProtocol,
+    pub device: Option<String>,
+    #[serde(default = "default_ebus_baud")]
+    pub baud: u32,
+    // also accepted as "load", its name at top level of old configs
+    #[serde(default = "default_definitions", alias = "load")]
+    pub definitions: Vec<String>,
+    // bytes read from interface at once
+    #[serde(default = "default_read_buffer_size")]
+    pub read_buffer_size: usize,
+    // cap of bytes queued in parser, oldest are dropped beyond it (losing a frame)
+    #[serde(default = "default_buffer_limit")]
+    pub buffer_limit: usize,
+    // seconds without a complete frame before the interface is reconnected,
+    // or the process exits with watchdog_exit (to be restarted by supervisor)
+    pub watchdog_timeout: Option<u64>,
+    #[serde(default)]
+    pub watchdog_exit: bool,
+    // longest request data accepted by parser
+    #[serde(default = "default_max_data_len")]
+    pub max_data_len: u8,
+    // pass frames with CRC error on (shown and captured, never decoded)
+    #[serde(default)]
+    pub deliver_crc_errors: bool,
+    // CRC of received frames: ebus, standard (CRC-8/SMBUS) or off
+    #[serde(default)]
+    pub crc: CrcMode,
+    // our master address (eg. "31"), enables MQTT commands of writable
+    // messages, needs enhanced protocol
+    pub write_address: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub topic: String,
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub pass: String,
+    // publish values retained, so new subscribers get last known values at once
+    #[serde(default)]
+    pub retain: bool,
+    // default QoS of publishes (0..=2), fields may override it with "qos"
+    #[serde(default)]
+    pub qos: u8,
+    // TLS transport, server verified against ca_cert or the system root store
+    // (also with client_cert)
+    #[serde(default)]
+    pub tls: bool,
+    pub ca_cert: Option<String>,
+    // client certificate authentication, both cert and key in PEM format
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    // stable id for persistent sessions and broker ACLs, "<hostname>-ebus" by default
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    #[serde(default = "default_clean_session")]
+    pub clean_session: bool,
+    // fallback brokers, tried in order when the one before is unreachable,
+    // host / port is the primary and used again once reachable
+    #[serde(default)]
+    pub brokers: Vec<BrokerConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BrokerConfig {
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MetricsConfig {
+    pub port: u16,
+    #[serde(default = "default_metrics_bind")]
+    pub bind: String,
+}
+
+fn default_capture_max_size() -> u64 { DEFAULT_CAPTURE_MAX_SIZE }
+fn default_ebus_host() -> String { "192.168.2.45".to_string() }
+fn default_ebus_port() -> u16 { 9999 }
+fn default_ebus_type() -> EbusType { EbusType::Tcp }
+fn default_ebus_baud() -> u32 { DEFAULT_SERIAL_BAUD }
+fn default_definitions() -> Vec<String> { vec!["./ariston.json".to_string()] }
+fn default_read_buffer_size() -> usize { 1024 }
+fn default_buffer_limit() -> usize { DEFAULT_BUFFER_LIMIT }
+fn default_max_data_len() -> u8 { MAX_DATA_LEN }
+fn default_metrics_bind() -> String { "0.0.0.0".to_string() }
+fn default_clean_session() -> bool { true }
+
+fn default_client_id() -> String {
+    let hostname = std::env::var("HOSTNAME").ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty());
+    match hostname {
+        Some(h) => format!("{}-ebus", h),
+        None => "ebus_mqtt".to_string()
+    }
+}
+
+impl Default for EbusConfig {
+    fn default() -> Self {
+        EbusConfig {
+            host: default_ebus_host(),
+            port: default_ebus_port(),
+            kind: default_ebus_type(),
+            protocol: EbusProtocol::default(),
+            device: None,
+            baud: default_ebus_baud(),
+            definitions: default_definitions(),
+            read_buffer_size: default_read_buffer_size(),
+            buffer_limit: default_buffer_limit(),
+            max_data_len: default_max_data_len(),
+            watchdog_timeout: None,
+            watchdog_exit: false,
+            deliver_crc_errors: false,
+            crc: CrcMode::default(),
+            write_address: None,
+        }
+    }
+}
+
+impl EbusConfig {
+    pub fn source(&self) -> EbusSource {
+        match self.kind {
+            // device presence is checked by Config::load
+            EbusType::Serial => EbusSource::Serial(self.device.clone().unwrap_or_default(), self.baud),
+            // IPv6 literals need brackets to be followed by port
+            EbusType::Tcp if self.host.contains(':') && !self.host.starts_with('[') => EbusSource::Tcp(format!("[{}]:{}", self.host, self.port)),
+            EbusType::Tcp => EbusSource::Tcp(format!("{}:{}", self.host, self.port)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    // path of offending field (eg. "mqtt.port") and what is wrong with it
+    Invalid(String, String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(file, e) => write!(f, "Failed to open {}: {}", file, e),
+            ConfigError::Invalid(path, e) => write!(f, "Invalid config, {}: {}", path, e)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn open_capture(&self) -> Option<CaptureLog> {
+        let filename = self.capture_file.as_ref()?;
+        match CaptureLog::open(filename, self.capture_max_size) {
+            Ok(capture) => Some(capture),
+            Err(e) => {
+                warn!("Failed to open capture file {}: {}", filename, e);
+                None
+            }
+        }
+    }
+
+    // parser set up with ebus options, without capture log
+    pub fn parser_options(&self) -> EbusParserBuilder {
+        EbusParserBuilder::new()
+            .buffer_limit(self.ebus.buffer_limit)
+            .max_data_len(self.ebus.max_data_len)
+            .protocol(self.ebus.protocol)
+            .crc_mode(self.ebus.crc)
+            .deliver_crc_errors(self.ebus.deliver_crc_errors)
+    }
+
+    // parser set up with ebus options and capture log
+    pub fn parser_builder(&self) -> EbusParserBuilder {
+        let builder = self.parser_options();
+        match self.open_capture() {
+            Some(capture) => builder.capture(capture),
+            None => builder
+        }
+    }
+
+    // addresses keys are checked by Config::load
+    pub fn address_names(&self) -> HashMap<u8, String> {
+        self.addresses.iter()
+            .filter_map(|(key, name)| Some((address::parse_address(key)?, name.clone())))
+            .collect()
+    }
+
+    // only_src / only_dest entries are checked by Config::load
+    pub fn address_filter(list: &Option<Vec<String>>) -> Option<Vec<u8>> {
+        list.as_ref().map(|l| l.iter().filter_map(|a| address::parse_address(a)).collect())
+    }
+
+    pub fn load(filename: &str) -> Result<Config, ConfigError> {
+        let file = File::open(filename).map_err(|e| ConfigError::Io(filename.to_string(), e))?;
+        let mut de = serde_json::Deserializer::from_reader(BufReader::new(file));
+        let mut cfg: Config = serde_path_to_error::deserialize(&mut de).map_err(|e| {
+            let path = e.path().to_string();
+            ConfigError::Invalid(path, e.into_inner().to_string())
+        })?;
+
+        // top-level "load" of old configs, file name or list of them, replaces
+        // the default definitions (extends ones given in ebus.definitions)
+        if let Some(load) = cfg.load.take() {
+            let files: Vec<String> = match load {
+                serde_json::Value::String(file) => vec![file],
+                serde_json::Value::Array(files) if files.iter().all(|f| f.is_string()) =>
+                    files.into_iter().filter_map(|f| f.as_str().map(|f| f.to_string())).collect(),
+                _ => return Err(ConfigError::Invalid("load".to_string(), "not a file name or list of them".to_string()))
+            };
+            warn!("Config: top-level \"load\" is deprecated, use ebus.definitions");
+            if cfg.ebus.definitions == default_definitions() {
+                cfg.ebus.definitions.clear();
+            }
+            cfg.ebus.definitions.extend(files.into_iter().filter(|f| !f.is_empty()));
+        }
+        if cfg.ebus.kind == EbusType::Serial && cfg.ebus.device.is_none() {
+            return Err(ConfigError::Invalid("ebus.device".to_string(), "required when ebus.type is serial".to_string()));
+        }
+        if cfg.ebus.read_buffer_size == 0 {
+            return Err(ConfigError::Invalid("ebus.read_buffer_size".to_string(), "must be greater than 0".to_string()));
+        }
+        if let Some(addr) = &cfg.ebus.write_address {
+            if !address::parse_address(addr).is_some_and(sender::is_master) {
+                return Err(ConfigError::Invalid("ebus.write_address".to_string(), "not a master address".to_string()));
+            }
+            if cfg.ebus.protocol != EbusProtocol::Enhanced {
+                return Err(ConfigError::Invalid("ebus.write_address".to_string(), "requires enhanced protocol".to_string()));
+            }
+        }
+        if let Some(key) = cfg.addresses.keys().find(|k| address::parse_address(k).is_none()) {
+            return Err(ConfigError::Invalid(format!("addresses.{}", key), "not a hex address".to_string()));
+        }
+        for (name, list) in [("only_src", &cfg.only_src), ("only_dest", &cfg.only_dest)] {
+            if let Some(i) = list.iter().flatten().position(|a| address::parse_address(a).is_none()) {
+                return Err(ConfigError::Invalid(format!("{}[{}]", name, i), "not a hex address".to_string()));
+            }
+        }
+        if let Some(mqtt) = &cfg.mqtt {
+            if mqtt.client_id.is_empty() {
+                return Err(ConfigError::Invalid("mqtt.client_id".to_string(), "must not be empty".to_string()));
+            }
+            if mqtt.qos > 2 {
+                return Err(ConfigError::Invalid("mqtt.qos".to_string(), "must be 0, 1 or 2".to_string()));
+            }
+            if mqtt.client_cert.is_some() != mqtt.client_key.is_some() {
+                return Err(ConfigError::Invalid("mqtt.client_key".to_string(), "client_cert and client_key must be given together".to_string()));
+            }
+        }
+        Ok(cfg)
+    }
+}
@@ -0,0 +1,261 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+
+use flate2::read::GzDecoder;
+use log::warn;
+
+
+#[derive(Debug)]
+pub enum DefinitionError {
+    Io(String, io::Error),
+    Parse(String, serde_json::Error),
+}
+
+impl Display for DefinitionError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DefinitionError::Io(file, e) => write!(f, "Failed to open {}: {}", file, e),
+            DefinitionError::Parse(file, e) => write!(f, "Failed to parse {}: {}", file, e)
+        }
+    }
+}
+
+impl std::error::Error for DefinitionError {}
+
+// loads definition file as untyped json, *.gz files are decompressed on the fly,
+// ebusd *.csv message files are converted into the same structure
+pub fn load(filename: &str) -> Result<serde_json::Value, DefinitionError> {
+    let file = File::open(filename).map_err(|e| DefinitionError::Io(filename.to_string(), e))?;
+    let name = filename.strip_suffix(".gz").unwrap_or(filename);
+    let reader: Box<dyn Read> = if name.len() < filename.len() {
+        Box::new(GzDecoder::new(BufReader::new(file)))
+    } else {
+        Box::new(file)
+    };
+    let mut reader = BufReader::new(reader);
+    if let Some(stem) = name.strip_suffix(".csv") {
+        let mut text = String::new();
+        reader.read_to_string(&mut text).map_err(|e| DefinitionError::Io(filename.to_string(), e))?;
+        let appliance = stem.rsplit('/').next().unwrap_or(stem);
+        return Ok(from_ebusd_csv(appliance, &text));
+    }
+    serde_json::from_reader(reader).map_err(|e| DefinitionError::Parse(filename.to_string(), e))
+}
+
+/*
+    ebusd message definition CSV, one message per line:
+    type,circuit,name,comment,QQ,ZZ,PBSB,ID,<field>,<field>...
+    where each field is 6 columns: name,part,type,divider/values,unit,comment
+
+    QQ / ZZ default to any address, ID bytes are matched as prefix of request data.
+    Fields are laid out one after another, master part (m) in request data after ID,
+    slave part (s) in response data. Part defaults to s for read messages, m otherwise.
+    A field without name is named like the message, several ones <message>_<field number>.
+    Lines with unsupported types (eg. templates) keep the fields before them.
+ */
+pub fn from_ebusd_csv(appliance: &str, text: &str) -> serde_json::Value {
+    let mut circuits: Vec<serde_json::Value> = Vec::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        // comments and default lines
+        if line.is_empty() || line.starts_with('#') || line.starts_with('*') {
+            continue;
+        }
+        let cols = split_csv(line);
+        if cols.len() < 8 {
+            warn!("ebusd csv line {}: expected at least 8 columns, skipped", lineno + 1);
+            continue;
+        }
+        let (kind, circuit_name, name) = (cols[0].as_str(), cols[1].as_str(), cols[2].as_str());
+        let Some(msg) = ebusd_message(kind, name, &cols, lineno + 1) else {
+            continue;
+        };
+        let circuit_name = if circuit_name.is_empty() { appliance } else { circuit_name };
+        match circuits.iter_mut().find(|c| c["name"] == circuit_name) {
+            Some(circuit) => circuit["messages"].as_array_mut().unwrap().push(msg),
+            None => circuits.push(serde_json::json!({ "name": circuit_name, "messages": [msg] })),
+        }
+    }
+    serde_json::json!({
+        "appliance": appliance,
+        "bus": "ebus",
+        "circuits": circuits,
+    })
+}
+
+fn ebusd_message(kind: &str, name: &str, cols: &[String], lineno: usize) -> Option<serde_json::Value> {
+    let any = |v: &str| if v.is_empty() { "*".to_string() } else { v.to_uppercase() };
+    let pbsb = cols[6].to_uppercase();
+    if pbsb.len() != 4 || u16::from_str_radix(&pbsb, 16).is_err() {
+        warn!("ebusd csv line {}: invalid PBSB {}, skipped", lineno, cols[6]);
+        return None;
+    }
+    let id = cols[7].to_uppercase();
+    let mut msg = serde_json::json!({
+        "comment": name,
+        "request_match": {
+            "src": any(&cols[4]),
+            "dst": any(&cols[5]),
+            "pbsb": pbsb,
+            "data": if id.is_empty() { "*".to_string() } else { format!("^{}", id) },
+        },
+    });
+
+    let default_part = if kind.trim_start_matches(|c: char| c.is_ascii_digit()).starts_with('r') { "s" } else { "m" };
+    let mut request_map = Vec::new();
+    let mut response_map = Vec::new();
+    // next free byte of master / slave data, and last bit used of a BIx byte run
+    let mut offsets = [id.len() / 2, 0];
+    let mut last_bit: [Option<u8>; 2] = [None, None];
+    // (part, position in its map) of fields without a name
+    let mut unnamed = Vec::new();
+    for (i, field) in cols[8..].chunks(6).enumerate() {
+        let col = |i: usize| field.get(i).map(String::as_str).unwrap_or("");
+        if col(2).is_empty() {
+            continue;
+        }
+        let part = if col(1).is_empty() { default_part } else { col(1) };
+        let p = if part == "m" { 0 } else { 1 };
+        let Some((data_type, width, type_factor)) = ebusd_type(col(2)) else {
+            warn!("ebusd csv line {}: unsupported type {}, remaining fields skipped", lineno, col(2));
+            break;
+        };
+        // consecutive BIx fields share one byte while bit numbers go up
+        let bit = col(2).strip_prefix("BI").and_then(|b| b.get(..1)).and_then(|b| b.parse::<u8>().ok());
+        if let Some(prev) = last_bit[p] {
+            if bit.is_none_or(|b| b <= prev) {
+                offsets[p] += 1;
+            }
+        }
+        last_bit[p] = bit;
+        if data_type == "ignore" {
+            offsets[p] += width;
+            continue;
+        }
+
+        // unnamed fields are told apart by their number in the line
+        let field_name = if col(0).is_empty() {
+            unnamed.push((p, if p == 0 { request_map.len() } else { response_map.len() }));
+            format!("{}_{}", name, i)
+        } else {
+            col(0).to_string()
+        };
+        let mut def = serde_json::json!({
+            "field_name": field_name,
+            "field_offset": offsets[p],
+            "data_type": data_type,
+            "unit": col(4),
+        });
+        // positive divider is kept as divisor, values like 0.1 aren't exact as factor
+        match col(3).parse::<f64>() {
+            Ok(d) if d > 0.0 && type_factor == 1.0 => def["divisor"] = d.into(),
+            _ => def["factor"] = (type_factor * ebusd_divider(col(3))).into(),
+        }
+        if let Some(values) = ebusd_values(col(3)) {
+            def["values"] = values;
+        }
+        match data_type {
+            "hex" | "ascii" => def["field_length"] = width.into(),
+            "bit" | "bits" => {
+                def["bit_offset"] = bit.unwrap_or(0).into();
+                if data_type == "bits" {
+                    def["bit_width"] = width.into();
+                }
+            }
+            _ => {}
+        }
+        if bit.is_none() {
+            offsets[p] += width;
+        }
+        if p == 0 { request_map.push(def) } else { response_map.push(def) }
+    }
+    // the only unnamed field is named like the message
+    if let [(p, i)] = unnamed[..] {
+        let map = if p == 0 { &mut request_map } else { &mut response_map };
+        map[i]["field_name"] = name.into();
+    }
+    if !request_map.is_empty() {
+        msg["request_map"] = request_map.into();
+    }
+    if !response_map.is_empty() {
+        msg["response_map"] = response_map.into();
+    }
+    Some(msg)
+}
+
+// ebusd data type -> (mapper data type, bytes (bits for BIx:n), factor)
+fn ebusd_type(ebusd: &str) -> Option<(&'static str, usize, f64)> {
+    let (base, len) = match ebusd.split_once(':') {
+        Some((base, len)) => (base, Some(len.parse::<usize>().ok()?)),
+        None => (ebusd, None)
+    };
+    let t = match (base, len) {
+        ("UCH", None) => ("u8", 1, 1.0),
+        ("SCH", None) => ("s8", 1, 1.0),
+        ("D1B", None) => ("DATA1b", 1, 1.0),
+        ("D1C", None) => ("DATA1c", 1, 1.0),
+        ("D2B", None) => ("DATA2b", 2, 1.0),
+        ("D2C", None) => ("DATA2c", 2, 1.0),
+        ("UIN", None) => ("u16le", 2, 1.0),
+        ("SIN", None) => ("s16le", 2, 1.0),
+        ("UIR", None) => ("u16he", 2, 1.0),
+        ("SIR", None) => ("s16he", 2, 1.0),
+        ("FLT", None) => ("s16le", 2, 0.001),
+        ("FLR", None) => ("s16he", 2, 0.001),
+        ("EXP", None) => ("f32le", 4, 1.0),
+        ("EXR", None) => ("f32be", 4, 1.0),
+        ("BCD", None) | ("BCD", Some(1)) => ("bcd", 1, 1.0),
+        ("BCD", Some(2)) => ("bcd16", 2, 1.0),
+        ("BTI", None) => ("bti", 3, 1.0),
+        ("BDA", None) | ("BDA", Some(4)) => ("bda", 4, 1.0),
+        ("BDA", Some(3)) => ("bda3", 3, 1.0),
+        ("HEX", Some(n)) => ("hex", n, 1.0),
+        ("STR", Some(n)) => ("ascii", n, 1.0),
+        ("IGN", n) => ("ignore", n.unwrap_or(1), 1.0),
+        (b, None) if b.len() == 3 && b.starts_with("BI") => ("bit", 1, 1.0),
+        (b, Some(n)) if b.len() == 3 && b.starts_with("BI") => ("bits", n, 1.0),
+        _ => return None
+    };
+    Some(t)
+}
+
+// divider column: positive divides, negative multiplies, value lists are ignored
+fn ebusd_divider(col: &str) -> f64 {
+    match col.parse::<f64>() {
+        Ok(d) if d > 0.0 => 1.0 / d,
+        Ok(d) if d < 0.0 => -d,
+        _ => 1.0
+    }
+}
+
+// "divider/values" column holding a value list ("0=off;1=on") -> values map
+fn ebusd_values(col: &str) -> Option<serde_json::Value> {
+    if !col.contains('=') {
+        return None;
+    }
+    let values: serde_json::Map<String, serde_json::Value> = col.split(';')
+        .filter_map(|v| v.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().into()))
+        .collect();
+    Some(values.into())
+}
+
+// splits csv line, double quoted columns may contain commas ("" is a quote)
+fn split_csv(line: &str) -> Vec<String> {
+    let mut cols = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                cols.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => cols.push(String::new()),
+            _ => cols.last_mut().unwrap().push(c)
+        }
+    }
+    cols.into_iter().map(|c| c.trim().to_string()).collect()
+}
@@ -1,2 +1,5 @@
+pub mod address;
+pub mod capture;
 pub mod crc8;
 pub mod parser;
+pub mod sender;
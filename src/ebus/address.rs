@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use super::parser::BROADCAST;
+
+
+// friendly names of bus addresses (config.json "addresses"), set once at startup
+// and shared with Display impls of telegrams
+static NAMES: OnceLock<HashMap<u8, String>> = OnceLock::new();
+
+// returns false when names were already set
+pub fn set_names(names: HashMap<u8, String>) -> bool {
+    NAMES.set(names).is_ok()
+}
+
+// true when any names were configured
+pub fn has_names() -> bool {
+    NAMES.get().is_some_and(|n| !n.is_empty())
+}
+
+// configured name, broadcast address is known without configuration
+pub fn name(addr: u8) -> Option<&'static str> {
+    match NAMES.get().and_then(|n| n.get(&addr)) {
+        Some(name) => Some(name.as_str()),
+        None if addr == BROADCAST => Some("Broadcast"),
+        None => None
+    }
+}
+
+// name of address, or its hex value when unknown
+pub fn display_name(addr: u8) -> String {
+    match name(addr) {
+        Some(name) => name.to_string(),
+        None => format!("{:02X}", addr)
+    }
+}
+
+// parses address key of config file, "10" or "0x10"
+pub fn parse_address(key: &str) -> Option<u8> {
+    let hex = key.strip_prefix("0x").or_else(|| key.strip_prefix("0X")).unwrap_or(key);
+    u8::from_str_radix(hex, 16).ok()
+}
@@ -0,0 +1,57 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+use super::parser::{EbusRequest, EbusResponse};
+
+pub const DEFAULT_CAPTURE_MAX_SIZE: u64 = 1024 * 1024;
+// number of rotated files kept (<file>.1 .. <file>.N)
+const CAPTURE_BACKUPS: u32 = 3;
+
+
+// appends every processed frame as a line, bytes as received (escaped):
+// <timestamp> <src dest pb sb len data.. crc> [<len data.. crc>]
+pub struct CaptureLog {
+    filename: String,
+    max_size: u64,
+    file: File,
+    size: u64,
+}
+
+impl CaptureLog {
+    pub fn open(filename: &str, max_size: u64) -> io::Result<CaptureLog> {
+        let file = OpenOptions::new().create(true).append(true).open(filename)?;
+        let size = file.metadata()?.len();
+        Ok(CaptureLog { filename: filename.to_string(), max_size, file, size })
+    }
+
+    pub fn write_frame(&mut self, req: &EbusRequest, resp: Option<&EbusResponse>) -> io::Result<()> {
+        let mut line = format!("{} {}", humantime::format_rfc3339_millis(SystemTime::now()), hex::encode_upper(req.raw()));
+        if let Some(r) = resp {
+            line.push(' ');
+            line.push_str(&hex::encode_upper(r.raw()));
+        }
+        line.push('\n');
+
+        if self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // <file>.N-1 -> <file>.N, ..., <file> -> <file>.1, then starts new file
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..CAPTURE_BACKUPS).rev() {
+            let from = format!("{}.{}", self.filename, i);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.filename, i + 1))?;
+            }
+        }
+        fs::rename(&self.filename, format!("{}.1", self.filename))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.filename)?;
+        self.size = 0;
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+// eBUS CRC8, polynomial 0x9B (x^8 + x^7 + x^4 + x^3 + x + 1), init 0
+// table is already precomputed: CRC_LOOKUP_TABLE[n] = n shifted through the polynomial 8 times
 const CRC_LOOKUP_TABLE : [u8; 256] = [
     0x00, 0x9b, 0xad, 0x36, 0xc1, 0x5a, 0x6c, 0xf7, 0x19, 0x82, 0xb4, 0x2f, 0xd8, 0x43, 0x75, 0xee,
     0x32, 0xa9, 0x9f, 0x04, 0xf3, 0x68, 0x5e, 0xc5, 0x2b, 0xb0, 0x86, 0x1d, 0xea, 0x71, 0x47, 0xdc,
@@ -17,7 +19,59 @@ const CRC_LOOKUP_TABLE : [u8; 256] = [
     0x95, 0x0e, 0x38, 0xa3, 0x54, 0xcf, 0xf9, 0x62, 0x8c, 0x17, 0x21, 0xba, 0x4d, 0xd6, 0xe0, 0x7b,
 ];
 
+// one table lookup per byte, no bitwise loop on the hot path
 pub fn update_crc(crc: u8, value: u8) -> u8 {
     CRC_LOOKUP_TABLE[crc as usize] ^ value
 }
 
+// bit by bit reference of update_crc, the table has to match it
+pub fn update_crc_bitwise(crc: u8, value: u8) -> u8 {
+    let mut crc = crc;
+    for _ in 0..8 {
+        crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x9B } else { crc << 1 };
+    }
+    crc ^ value
+}
+
+
+// CRC-8/SMBUS as used by generic gateways, polynomial 0x07, init 0
+const STANDARD_LOOKUP_TABLE: [u8; 256] = standard_table();
+
+const fn standard_table() -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+}
+
+pub fn update_crc_standard(crc: u8, value: u8) -> u8 {
+    STANDARD_LOOKUP_TABLE[(crc ^ value) as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_matches_bitwise() {
+        for seed in [0x00, 0x01, 0x5A, 0x9B, 0xAA, 0xFF] {
+            for value in 0..=255 {
+                assert_eq!(update_crc(seed, value), update_crc_bitwise(seed, value), "seed {:02X}, value {:02X}", seed, value);
+            }
+        }
+    }
+
+    #[test]
+    fn standard_check_value() {
+        assert_eq!(b"123456789".iter().fold(0, |crc, b| update_crc_standard(crc, *b)), 0xF4);
+    }
+}
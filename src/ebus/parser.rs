@@ -1,15 +1,55 @@
-use std::{collections::VecDeque, fmt::{self, Display, Formatter}};
+use std::{collections::VecDeque, fmt::{self, Display, Formatter}, sync::mpsc, time::{Duration, Instant, SystemTime}};
 
-use crate::log::*;
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
 
-use super::crc8::update_crc;
+use super::address;
+use super::capture::CaptureLog;
+use super::crc8::{update_crc, update_crc_standard};
+use super::sender::{is_master, BusEvent};
 
 
-const SYN: u8 = 0xAA;
-const ACK: u8 = 0x00;
-const NACK: u8 = 0xFF;
-const BROADCAST: u8 = 0xFE;
+pub(crate) const SYN: u8 = 0xAA;
+pub(crate) const ESC: u8 = 0xA9;
+pub(crate) const ACK: u8 = 0x00;
+pub(crate) const NACK: u8 = 0xFF;
+pub(crate) const BROADCAST: u8 = 0xFE;
 
+// default maximum of request LEN, longer frames are dropped as erroneous
+pub const MAX_DATA_LEN: u8 = 16;
+
+// default cap of bytes waiting in parser queues
+pub const DEFAULT_BUFFER_LIMIT: usize = 4096;
+
+// slave answers within milliseconds, anything arriving later after ACK
+// can't be its response (bus went quiet, bytes of a lost SYN)
+const RESPONSE_WAIT_MAX: Duration = Duration::from_secs(1);
+
+
+// interface stream format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EbusProtocol {
+    // ebusd enhanced protocol, bytes >= 0x80 come as command + data pairs
+    #[default]
+    Enhanced,
+    // every byte is a literal bus byte (eg. plain ebus-to-TCP bridge)
+    Raw,
+}
+
+// CRC checked on received frames
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CrcMode {
+    // eBUS CRC8 (polynomial 0x9B)
+    #[default]
+    Ebus,
+    // CRC-8/SMBUS (polynomial 0x07) over the same bytes, for gateways
+    // recomputing it
+    Standard,
+    // no check, every frame is valid (gateway has checked / stripped it)
+    Off,
+}
 
 enum EbusParserState {
     WaitingForSYN,
@@ -24,8 +64,9 @@ enum EbusParserState {
     WaitingForResponse
 }
 
+#[allow(dead_code)]
 #[repr(u8)]
-enum EnhProtoRequest {
+pub(crate) enum EnhProtoRequest {
     Init = 0,
     Send = 1,
     Start = 2,
@@ -43,27 +84,149 @@ enum EnhProtoResponse {
     ErrorHost = 0x0c
 }
 
+impl TryFrom<u8> for EnhProtoResponse {
+    type Error = u8;
+
+    // unknown command codes are returned back as error
+    fn try_from(cmd: u8) -> Result<Self, Self::Error> {
+        match cmd {
+            0 => Ok(EnhProtoResponse::Resetted),
+            1 => Ok(EnhProtoResponse::Received),
+            2 => Ok(EnhProtoResponse::Started),
+            3 => Ok(EnhProtoResponse::Info),
+            0x0a => Ok(EnhProtoResponse::Failed),
+            0x0b => Ok(EnhProtoResponse::ErrorEbus),
+            0x0c => Ok(EnhProtoResponse::ErrorHost),
+            _ => Err(cmd)
+        }
+    }
+}
+
+#[allow(dead_code)]
 #[repr(u8)]
 enum EnhProtoErrors {
     ErrorFraming = 0x00,
     ErrorBuffOverrun = 0x01,
 }
 
+// crc is calculated over bytes as transmitted, ie. with A9/AA escaped
+fn update_crc_escaped(mode: CrcMode, crc: u8, value: u8) -> u8 {
+    let update = if mode == CrcMode::Standard { update_crc_standard } else { update_crc };
+    match value {
+        ESC => update(update(crc, ESC), 0x00),
+        SYN => update(update(crc, ESC), 0x01),
+        _ => update(crc, value)
+    }
+}
+
+// escapes bytes for transmission: A9 -> A9 00, AA -> A9 01
+pub fn escape(bytes: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(bytes.len());
+    for b in bytes {
+        match *b {
+            ESC => escaped.extend([ESC, 0x00]),
+            SYN => escaped.extend([ESC, 0x01]),
+            _ => escaped.push(*b)
+        }
+    }
+    escaped
+}
+
+// telegram type, as told by destination address and presence of slave response
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TelegramKind {
+    Broadcast,
+    MasterMaster,
+    MasterSlave,
+}
+
+impl TelegramKind {
+    // kind expected for telegram sent to dest
+    pub fn from_dest(dest: u8) -> TelegramKind {
+        if dest == BROADCAST {
+            TelegramKind::Broadcast
+        } else if is_master(dest) {
+            TelegramKind::MasterMaster
+        } else {
+            TelegramKind::MasterSlave
+        }
+    }
+
+    // name used in definition files ("kind" of message)
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TelegramKind::Broadcast => "broadcast",
+            TelegramKind::MasterMaster => "master_master",
+            TelegramKind::MasterSlave => "master_slave",
+        }
+    }
+}
+
+// Clone, so frames can be kept beyond the callback (eg. collected by a test harness)
+#[derive(Debug, Clone)]
 pub struct EbusRequest {
     src : u8,
     dest : u8,
     pbsb: u16,
     len: u8,
     data: Vec<u8>,
-    crc: u8
+    crc: u8,
+    // crc calculated from received bytes, differs from crc only for frames
+    // delivered despite CRC error (EbusParser::set_deliver_crc_errors)
+    computed_crc: u8,
+    kind: TelegramKind,
+    // whether destination ACKed the request, None for broadcasts and
+    // telegrams not seen on the bus (EbusRequest::new)
+    acked: Option<bool>,
+    // when last byte of frame was read
+    timestamp: SystemTime,
+    // bus idle time from end of previous frame to src of this one
+    gap: Option<Duration>,
+    // bytes as received, src through crc, still escaped
+    raw: Vec<u8>
 }
+#[derive(Debug, Clone)]
 pub struct EbusResponse {
     len: u8,
     data: Vec<u8>,
-    crc: u8
+    crc: u8,
+    computed_crc: u8,
+    // time from ACK of request to first byte of response, as seen by the reader
+    latency: Option<Duration>,
+    // bytes as received, len through crc, still escaped
+    raw: Vec<u8>
 }
 
 impl EbusRequest {
+    pub fn new(src: u8, dest: u8, pbsb: u16, data: &[u8]) -> EbusRequest {
+        let mut req = EbusRequest {
+            src,
+            dest,
+            pbsb,
+            len: data.len() as u8,
+            data: data.to_vec(),
+            crc: 0,
+            computed_crc: 0,
+            kind: TelegramKind::from_dest(dest),
+            acked: None,
+            timestamp: SystemTime::now(),
+            gap: None,
+            raw: Vec::new()
+        };
+        req.crc = req.calc_crc8(CrcMode::Ebus);
+        req.computed_crc = req.crc;
+        req.raw = escape(&req.to_bytes());
+        req
+    }
+
+    // telegram bytes as sent on the bus: src dest pb sb len data.. crc
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.src, self.dest, self.pb(), self.sb(), self.len];
+        bytes.extend(&self.data);
+        bytes.push(self.crc);
+        bytes
+    }
+
     fn clear(&mut self) {
         self.src = 0;
         self.dest = 0;
@@ -71,17 +234,26 @@ impl EbusRequest {
         self.len = 0;
         self.data.clear();
         self.crc = 0;
+        self.computed_crc = 0;
+        self.kind = TelegramKind::MasterMaster;
+        self.acked = None;
+        self.gap = None;
+        self.raw.clear();
     }
 
-    fn calc_crc8(&self) -> u8 {
+    // received crc itself with CrcMode::Off
+    fn calc_crc8(&self, mode: CrcMode) -> u8 {
+        if mode == CrcMode::Off {
+            return self.crc;
+        }
         let mut crc: u8 = 0;
-        crc = update_crc(crc, self.src);
-        crc = update_crc(crc, self.dest);
-        crc = update_crc(crc, (self.pbsb >> 8) as u8);
-        crc = update_crc(crc, (self.pbsb & 0xFF) as u8);
-        crc = update_crc(crc, self.len);        
+        crc = update_crc_escaped(mode, crc, self.src);
+        crc = update_crc_escaped(mode, crc, self.dest);
+        crc = update_crc_escaped(mode, crc, (self.pbsb >> 8) as u8);
+        crc = update_crc_escaped(mode, crc, (self.pbsb & 0xFF) as u8);
+        crc = update_crc_escaped(mode, crc, self.len);        
         for b in &self.data {
-            crc = update_crc(crc, *b);
+            crc = update_crc_escaped(mode, crc, *b);
         }
         crc
     }
@@ -90,25 +262,40 @@ impl EbusRequest {
         self.src
     }
     pub fn src_hex(&self) -> String {
-        format!("{:X?}", self.src)
+        format!("{:02X}", self.src)
     }
     pub fn dest(&self) -> u8 {
         self.dest
     }
     pub fn dest_hex(&self) -> String {
-        format!("{:X?}", self.dest)
+        format!("{:02X}", self.dest)
     }
     pub fn pbsb(&self) -> u16 {
         self.pbsb
     }
     pub fn pbsb_hex(&self) -> String {
-        format!("{:X?}", self.pbsb)
+        format!("{:04X}", self.pbsb)
+    }
+    pub fn pb(&self) -> u8 {
+        (self.pbsb >> 8) as u8
+    }
+    pub fn pb_hex(&self) -> String {
+        format!("{:02X}", self.pb())
+    }
+    pub fn sb(&self) -> u8 {
+        (self.pbsb & 0xFF) as u8
+    }
+    pub fn sb_hex(&self) -> String {
+        format!("{:02X}", self.sb())
     }
     pub fn len(&self) -> u8 {
         self.len
     }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
     pub fn len_hex(&self) -> String {
-        format!("{:X?}", self.len)
+        format!("{:02X}", self.len)
     }
     pub fn data(&self) -> &Vec<u8> {
         &self.data
@@ -116,20 +303,67 @@ impl EbusRequest {
     pub fn data_hex(&self) -> String {
         hex::encode_upper(&self.data)
     }
+    pub fn kind(&self) -> TelegramKind {
+        self.kind
+    }
+    pub fn acked(&self) -> Option<bool> {
+        self.acked
+    }
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+    // None for the first frame seen
+    pub fn gap(&self) -> Option<Duration> {
+        self.gap
+    }
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+    pub fn crc(&self) -> u8 {
+        self.crc
+    }
+    pub fn computed_crc(&self) -> u8 {
+        self.computed_crc
+    }
+    pub fn crc_valid(&self) -> bool {
+        self.crc == self.computed_crc
+    }
 }
 
 impl EbusResponse {
+    // response as a slave would send it, crc calculated
+    pub fn new(data: &[u8]) -> EbusResponse {
+        let mut resp = EbusResponse {
+            len: data.len() as u8,
+            data: data.to_vec(),
+            crc: 0,
+            computed_crc: 0,
+            latency: None,
+            raw: Vec::new()
+        };
+        resp.crc = resp.calc_crc8(CrcMode::Ebus);
+        resp.computed_crc = resp.crc;
+        resp.raw = escape(&resp.to_bytes());
+        resp
+    }
+
     fn clear(&mut self) {
         self.len = 0;
         self.data.clear();
         self.crc = 0;
+        self.computed_crc = 0;
+        self.latency = None;
+        self.raw.clear();
     }
 
-    fn calc_crc8(&self) -> u8 {
+    fn calc_crc8(&self, mode: CrcMode) -> u8 {
+        if mode == CrcMode::Off {
+            return self.crc;
+        }
         let mut crc: u8 = 0;
-        crc = update_crc(crc, self.len);
+        crc = update_crc_escaped(mode, crc, self.len);
         for b in &self.data {
-            crc = update_crc(crc, *b);
+            crc = update_crc_escaped(mode, crc, *b);
         }
         crc
     }
@@ -137,8 +371,11 @@ impl EbusResponse {
     pub fn len(&self) -> u8 {
         self.len
     }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
     pub fn len_hex(&self) -> String {
-        format!("{:X?}", self.len)
+        format!("{:02X}", self.len)
     }
     pub fn data(&self) -> &Vec<u8> {
         &self.data
@@ -146,19 +383,57 @@ impl EbusResponse {
     pub fn data_hex(&self) -> String {
         hex::encode_upper(&self.data)
     }
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+    pub fn crc(&self) -> u8 {
+        self.crc
+    }
+    pub fn computed_crc(&self) -> u8 {
+        self.computed_crc
+    }
+    pub fn crc_valid(&self) -> bool {
+        self.crc == self.computed_crc
+    }
+
+    // response bytes as sent on the bus: len data.. crc
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.len];
+        bytes.extend(&self.data);
+        bytes.push(self.crc);
+        bytes
+    }
 
 }
 
 impl Display for EbusRequest {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "Req: [src: {:02X}, dest: {:02X}, pbsb: {:04X}, len: {:02X}, data: {:02X?}, crc: {:02X}]", 
-            self.src, self.dest, self.pbsb, self.len, self.data, self.crc)
+        write!(f, "Req: [src: {:02X}", self.src)?;
+        if let Some(name) = address::name(self.src) {
+            write!(f, " ({})", name)?;
+        }
+        write!(f, ", dest: {:02X}", self.dest)?;
+        if let Some(name) = address::name(self.dest) {
+            write!(f, " ({})", name)?;
+        }
+        write!(f, ", pbsb: {:04X}, len: {:02X}, data: {:02X?}, crc: {:02X}", self.pbsb, self.len, self.data, self.crc)?;
+        if !self.crc_valid() {
+            write!(f, " (invalid, computed {:02X})", self.computed_crc)?;
+        }
+        write!(f, "]")
     }
 }
 
 impl Display for EbusResponse {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "Resp: [len: {:02X}, data: {:02X?}, crc: {:02X}]", self.len, self.data, self.crc)
+        write!(f, "Resp: [len: {:02X}, data: {:02X?}, crc: {:02X}", self.len, self.data, self.crc)?;
+        if !self.crc_valid() {
+            write!(f, " (invalid, computed {:02X})", self.computed_crc)?;
+        }
+        write!(f, "]")
     }
 }
 
@@ -176,56 +451,330 @@ impl Display for EbusData {
     }
 }
 
-pub type EbusCallback = dyn FnMut(&EbusRequest, Option<&EbusResponse>);
+// parser counters, for bus health monitoring
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Stats {
+    pub frames: u64,
+    pub crc_errors: u64,
+    pub nacks: u64,
+    pub broadcasts: u64,
+    pub malformed: u64,
+    // bytes dropped because parser queues were full
+    pub dropped: u64,
+}
+
+// Send so that a parser can move into a thread or task (source::read_async)
+pub type EbusCallback = dyn FnMut(&EbusRequest, Option<&EbusResponse>) + Send;
+
+// reason of dropped frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameError {
+    // crc computed from received bytes vs crc byte in frame
+    Crc { expected: u8, received: u8 },
+    // LEN byte over allowed maximum
+    TooLong(u8),
+    // byte following A9 escape other than 00 / 01
+    InvalidEscape(u8),
+    Nack,
+    // SYN before frame was complete (arbitration collision, master gave up)
+    Interrupted,
+    // first byte after SYN is not a master address
+    InvalidSource(u8),
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            FrameError::Crc { expected, received } => write!(f, "CRC error (expected {:02X}, received {:02X})", expected, received),
+            FrameError::TooLong(len) => write!(f, "LEN {:02X} too long", len),
+            FrameError::InvalidEscape(b) => write!(f, "invalid escape sequence A9 {:02X}", b),
+            FrameError::Nack => write!(f, "NACK"),
+            FrameError::Interrupted => write!(f, "interrupted by SYN"),
+            FrameError::InvalidSource(src) => write!(f, "source {:02X} is not a master address", src)
+        }
+    }
+}
+
+// called with partially parsed frame before it is dropped,
+// response is present only when error happened inside of it
+pub type EbusErrorCallback = dyn FnMut(&FrameError, &EbusRequest, Option<&EbusResponse>) + Send;
 
 pub struct EbusParser {
     state: EbusParserState,
     request: EbusRequest,
     response: EbusResponse,
-    incoming_data_len: i8,
-    incoming: VecDeque<u8>,
-    buffer: VecDeque<EbusData>,
+    incoming_data_len: u8,
+    // bytes with the time they were read
+    incoming: VecDeque<(u8, Instant)>,
+    buffer: VecDeque<(EbusData, Instant)>,
+    // read time of byte being parsed, frames are timed by it rather than by
+    // when they're parsed
+    byte_at: Instant,
     got_response: bool,
     ack_received: bool,
     got_broadcast: bool,
+    escape_pending: bool,
+    // when request was ACKed, for response latency
+    ack_at: Option<Instant>,
     callback: Box<EbusCallback>,
+    error_callback: Option<Box<EbusErrorCallback>>,
+    crc_error_callback: Option<Box<EbusCallback>>,
+    events: Option<mpsc::Sender<BusEvent>>,
+    capture: Option<CaptureLog>,
+    stats: Stats,
+    buffer_limit: usize,
+    max_data_len: u8,
+    protocol: EbusProtocol,
+    crc_mode: CrcMode,
+    last_frame: Option<Instant>,
+    deliver_crc_errors: bool,
+    flush_threshold: usize,
 }
 
 // function to decode enhanced protocol data from ebus interface
 // if receivced byte is >= 0x80 then it is should be decoded into 1 byte as follows: 
 // byte = (byte1 - 0xc0) << 6 + (byte2 - 0x80)
-fn decode_enhproto_tuple(b1:u8, b2:u8) -> (u8, u8) {
-    let data = ((b1 - 0xc0) << 6) + (b2 - 0x80);
-    let cmd: u8 = (b1-0xc0) >> 2;
+// (masked instead of subtracted, so noisy bytes can't overflow)
+// (bit layout described at encode_enhproto_tuple)
+pub fn decode_enhproto_tuple(b1:u8, b2:u8) -> (u8, u8) {
+    let data = ((b1 & 0x03) << 6) | (b2 & 0x3f);
+    let cmd: u8 = (b1 & 0x3f) >> 2;
     (cmd, data)
 }
 
+// function to encode command + data byte into enhanced protocol tuple,
+// exact inverse of decode_enhproto_tuple (cmd is 4 bits, 0..=0x0f)
+//
+//          byte1                   byte2
+//   7 6 | 5 4 3 2 | 1 0      7 6 | 5 4 3 2 1 0
+//   1 1 |   cmd   | d7 d6    1 0 | d5 .. d0
+pub fn encode_enhproto_tuple(cmd: u8, data: u8) -> (u8, u8) {
+    let b1 = 0xc0 | ((cmd & 0x0f) << 2) | (data >> 6);
+    let b2 = 0x80 | (data & 0x3f);
+    (b1, b2)
+}
+
+
+// parser options set up front, chained:
+// EbusParserBuilder::new().protocol(EbusProtocol::Raw).max_data_len(32).build(callback)
+pub struct EbusParserBuilder {
+    error_callback: Option<Box<EbusErrorCallback>>,
+    crc_error_callback: Option<Box<EbusCallback>>,
+    events: Option<mpsc::Sender<BusEvent>>,
+    capture: Option<CaptureLog>,
+    buffer_limit: usize,
+    max_data_len: u8,
+    protocol: EbusProtocol,
+    crc_mode: CrcMode,
+    deliver_crc_errors: bool,
+    flush_threshold: usize,
+}
+
+impl Default for EbusParserBuilder {
+    fn default() -> Self {
+        EbusParserBuilder {
+            error_callback: None,
+            crc_error_callback: None,
+            events: None,
+            capture: None,
+            buffer_limit: DEFAULT_BUFFER_LIMIT,
+            max_data_len: MAX_DATA_LEN,
+            protocol: EbusProtocol::Enhanced,
+            crc_mode: CrcMode::Ebus,
+            deliver_crc_errors: false,
+            flush_threshold: 1,
+        }
+    }
+}
+
+// same meaning as EbusParser::set_* methods
+impl EbusParserBuilder {
+    pub fn new() -> EbusParserBuilder {
+        EbusParserBuilder::default()
+    }
+
+    pub fn max_data_len(mut self, len: u8) -> Self {
+        self.max_data_len = len;
+        self
+    }
+
+    pub fn protocol(mut self, protocol: EbusProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn crc_mode(mut self, mode: CrcMode) -> Self {
+        self.crc_mode = mode;
+        self
+    }
+
+    pub fn buffer_limit(mut self, limit: usize) -> Self {
+        self.buffer_limit = limit.max(2);
+        self
+    }
+
+    pub fn deliver_crc_errors(mut self, deliver: bool) -> Self {
+        self.deliver_crc_errors = deliver;
+        self
+    }
+
+    pub fn flush_threshold(mut self, bytes: usize) -> Self {
+        self.flush_threshold = bytes.max(1);
+        self
+    }
+
+    pub fn capture(mut self, capture: CaptureLog) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    pub fn event_sink(mut self, events: mpsc::Sender<BusEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    // called for every dropped frame, CRC errors included (FrameError::Crc)
+    pub fn on_error(mut self, cb : impl FnMut(&FrameError, &EbusRequest, Option<&EbusResponse>) + Send + 'static) -> Self {
+        self.error_callback = Some(Box::new(cb));
+        self
+    }
+
+    pub fn on_crc_error(mut self, cb : impl FnMut(&EbusRequest, Option<&EbusResponse>) + Send + 'static) -> Self {
+        self.crc_error_callback = Some(Box::new(cb));
+        self
+    }
+
+    pub fn build(self, cb : impl FnMut(&EbusRequest, Option<&EbusResponse>) + Send + 'static) -> EbusParser {
+            EbusParser {
+                state: EbusParserState::WaitingForSYN,
+                request: EbusRequest {
+                    src: 0,
+                    dest: 0,
+                    pbsb: 0,
+                    len: 0,
+                    data: Vec::new(),
+                    crc: 0,
+                    computed_crc: 0,
+                    kind: TelegramKind::MasterMaster,
+                    acked: None,
+                    timestamp: SystemTime::UNIX_EPOCH,
+                    gap: None,
+                    raw: Vec::new()
+                },
+                response: EbusResponse {
+                    len: 0,
+                    data: Vec::new(),
+                    crc: 0,
+                    computed_crc: 0,
+                    latency: None,
+                    raw: Vec::new()
+                },
+                incoming: VecDeque::new(),
+                buffer: VecDeque::new(),
+                byte_at: Instant::now(),
+                incoming_data_len: 0,
+                got_response: false,
+                ack_received: false,
+                got_broadcast: false,
+                escape_pending: false,
+                ack_at: None,
+                // callback: Box::new(move |_,_| { cb() })
+                callback: Box::new(cb),
+                error_callback: self.error_callback,
+                crc_error_callback: self.crc_error_callback,
+                events: self.events,
+                capture: self.capture,
+                stats: Stats::default(),
+                buffer_limit: self.buffer_limit,
+                max_data_len: self.max_data_len,
+                protocol: self.protocol,
+                crc_mode: self.crc_mode,
+                last_frame: None,
+                deliver_crc_errors: self.deliver_crc_errors,
+                flush_threshold: self.flush_threshold
+            }
+    }
+}
 
 impl EbusParser {
-    pub fn new(cb : impl FnMut(&EbusRequest, Option<&EbusResponse>) + 'static) -> EbusParser {
-        EbusParser {
-            state: EbusParserState::WaitingForSYN,
-            request: EbusRequest {
-                src: 0,
-                dest: 0,
-                pbsb: 0,
-                len: 0,
-                data: Vec::new(),
-                crc: 0
-            },
-            response: EbusResponse {
-                len: 0,
-                data: Vec::new(),
-                crc: 0
-            },
-            incoming: VecDeque::new(),
-            buffer: VecDeque::new(),
-            incoming_data_len: 0,
-            got_response: false,
-            ack_received: false,
-            got_broadcast: false,
-            // callback: Box::new(move |_,_| { cb() })
-            callback: Box::new(cb)
+    // parser with default options, see EbusParserBuilder
+    pub fn new(cb : impl FnMut(&EbusRequest, Option<&EbusResponse>) + Send + 'static) -> EbusParser {
+        EbusParserBuilder::new().build(cb)
+    }
+
+    // writes every processed frame into capture log, independent of log level
+    pub fn set_capture(&mut self, capture: CaptureLog) {
+        self.capture = Some(capture);
+    }
+
+    // caps bytes queued in parser, oldest ones are dropped when exceeded
+    // (costs the frame being received, parser resyncs on next SYN)
+    pub fn set_buffer_limit(&mut self, limit: usize) {
+        self.buffer_limit = limit.max(2);
+    }
+
+    // for devices using extended messages longer than MAX_DATA_LEN
+    pub fn set_max_data_len(&mut self, len: u8) {
+        self.max_data_len = len;
+    }
+
+    pub fn set_protocol(&mut self, protocol: EbusProtocol) {
+        self.protocol = protocol;
+    }
+
+    // for gateways presenting frames with another CRC or an unchecked one
+    pub fn set_crc_mode(&mut self, mode: CrcMode) {
+        self.crc_mode = mode;
+    }
+
+    // frames failing CRC check are delivered to callback (and capture) marked by
+    // crc_valid() == false instead of being dropped, for diagnosing bad wiring
+    pub fn set_deliver_crc_errors(&mut self, deliver: bool) {
+        self.deliver_crc_errors = deliver;
+    }
+
+    // incoming bytes are parsed once at least this many are queued (1, right
+    // away, by default); fewer stay until next feed or flush
+    pub fn set_flush_threshold(&mut self, bytes: usize) {
+        self.flush_threshold = bytes.max(1);
+    }
+
+    // when last complete frame was processed, for detecting a silent bus
+    pub fn last_frame(&self) -> Option<Instant> {
+        self.last_frame
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn set_error_callback(&mut self, cb : impl FnMut(&FrameError, &EbusRequest, Option<&EbusResponse>) + Send + 'static) {
+        self.error_callback = Some(Box::new(cb));
+    }
+
+    // called with every frame failing CRC check (crc() against computed_crc()),
+    // whether it's dropped or delivered
+    pub fn set_crc_error_callback(&mut self, cb : impl FnMut(&EbusRequest, Option<&EbusResponse>) + Send + 'static) {
+        self.crc_error_callback = Some(Box::new(cb));
+    }
+
+    fn frame_error(&mut self, error: FrameError) {
+        debug!("Dropping frame: {}", error);
+        if let Some(cb) = &mut self.error_callback {
+            let resp = if self.got_response { Some(&self.response) } else { None };
+            cb(&error, &self.request, resp);
+        }
+    }
+
+    // forwards enhanced protocol responses (arbitration result, received bytes)
+    // to EbusSender, needed for sending telegrams
+    pub fn set_event_sink(&mut self, events: mpsc::Sender<BusEvent>) {
+        self.events = Some(events);
+    }
+
+    fn emit(&self, event: BusEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
         }
     }
 
@@ -233,75 +782,174 @@ impl EbusParser {
         self.state = EbusParserState::WaitingForSYN;
         self.request.clear();
         self.response.clear();
-        self.incoming.clear();
-        self.buffer.clear();
         self.incoming_data_len = 0;
         self.got_response = false;
         self.ack_received = false;
         self.got_broadcast = false;
+        self.escape_pending = false;
+        self.ack_at = None;
+    }
+
+    // back to waiting for SYN with all buffered bytes dropped, eg. after
+    // reconnect or when out of sync; any frame in flight is discarded
+    // (without error callback), stats and callbacks are kept
+    pub fn reset(&mut self) {
+        self.clear();
+        self.incoming.clear();
+        self.buffer.clear();
     }
 
+    // parses data once flush threshold is reached (right away by default), only
+    // the first byte of an enhanced protocol pair split between reads is kept
+    // until the next feed
     pub fn feed(&mut self, data: &[u8], len: usize) {
-        for i in 0..len {
-            self.incoming.push_back(data[i]);
+        self.feed_at(data, len, Instant::now());
+    }
+
+    // feed of bytes read at `received`
+    pub fn feed_at(&mut self, data: &[u8], len: usize, received: Instant) {
+        self.incoming.extend(data[..len].iter().map(|b| (*b, received)));
+        if self.incoming.len() > self.buffer_limit {
+            let excess = self.incoming.len() - self.buffer_limit;
+            warn!("Parser buffer full, dropping {} bytes", excess);
+            self.incoming.drain(..excess);
+            self.stats.dropped += excess as u64;
         }
-        if self.incoming.len() > 64 {
+        if self.incoming.len() >= self.flush_threshold {
             self.parse_incoming_data();
         }
     }
 
+    // parses all pending bytes, eg. at the end of input
+    pub fn flush(&mut self) {
+        self.parse_incoming_data();
+    }
+
     fn parse_incoming_data(&mut self) {
-        logD(format!("\n\nIncoming: {:X?}", self.incoming));
+        trace!("Incoming: {:X?}", self.incoming.iter().map(|(b, _)| b).collect::<Vec<_>>());
 
         // process incoming data loop
-        loop {
-            // pop first byte 
-            let b1 = match self.incoming.pop_front() {
-                Some(b) => b,
-                None => break
-            };
-            if (b1 & 0xC0) == 0xC0 {
+        // pop first byte 
+        while let Some((b1, at)) = self.incoming.pop_front() {
+            if self.protocol == EbusProtocol::Raw {
+                self.buffer.push_back((EbusData::PureByte(b1), at));
+            } else if (b1 & 0xC0) == 0xC0 {
                 // pop next byte
-                let b2 = match self.incoming.pop_front() {
+                let (b2, at) = match self.incoming.pop_front() {
                     Some(b) => b,
-                    None => break
+                    None => {
+                        // second byte not received yet
+                        self.incoming.push_front((b1, at));
+                        break;
+                    }
                 };
                 if (b2 & 0x80) == 0x80 {
                     let (cmd, data) = decode_enhproto_tuple(b1,b2);
-                    let cmd_e = unsafe { std::mem::transmute::<u8, EnhProtoResponse>(cmd) };
-                    match cmd_e  {
-                        EnhProtoResponse::Resetted => logln(LogLevel::Debug, " -= Comm resetted. =- ".to_string()),
-                        EnhProtoResponse::Received => { self.buffer.push_back(EbusData::EnhancedProtocol(cmd, data)); }
-                        EnhProtoResponse::Started => logln(LogLevel::Debug, "Arbitration started. ".to_string()),
-                        EnhProtoResponse::Info => logln(LogLevel::Debug, "Info arrived. ".to_string()),
-                        EnhProtoResponse::Failed => logln(LogLevel::Debug, "Failed. ".to_string()),
-                        EnhProtoResponse::ErrorEbus => logln(LogLevel::Debug,"Comm error ebus. ".to_string()),
-                        EnhProtoResponse::ErrorHost => logln(LogLevel::Debug,"Comm error host. ".to_string()),
+                    match EnhProtoResponse::try_from(cmd) {
+                        Ok(EnhProtoResponse::Resetted) => debug!(" -= Comm resetted. =- "),
+                        Ok(EnhProtoResponse::Received) => {
+                            self.emit(BusEvent::Received(data));
+                            self.buffer.push_back((EbusData::EnhancedProtocol(cmd, data), at));
+                        }
+                        Ok(EnhProtoResponse::Started) => {
+                            debug!("Arbitration started. ");
+                            self.emit(BusEvent::Started(data));
+                        }
+                        Ok(EnhProtoResponse::Info) => debug!("Info arrived. "),
+                        Ok(EnhProtoResponse::Failed) => {
+                            debug!("Failed. ");
+                            self.emit(BusEvent::Failed(data));
+                        }
+                        Ok(EnhProtoResponse::ErrorEbus) => {
+                            debug!("Comm error ebus. ");
+                            self.emit(BusEvent::ErrorEbus(data));
+                        }
+                        Ok(EnhProtoResponse::ErrorHost) => {
+                            debug!("Comm error host. ");
+                            self.emit(BusEvent::ErrorHost(data));
+                        }
+                        Err(unknown) => {
+                            debug!("EnhProto unknown command {:02X}, skipped.", unknown);
+                            self.stats.malformed += 1;
+                        }
                     }
                 } else {
-                    logln(LogLevel::Debug,"EnhProto ERROR!".to_string());
+                    // first byte lost its partner, second one is decoded on its own
+                    debug!("EnhProto ERROR! Orphaned {:02X}, followed by {:02X}", b1, b2);
+                    self.stats.malformed += 1;
+                    self.incoming.push_front((b2, at));
                 }
             } else {
-                self.buffer.push_back(EbusData::PureByte(b1));
+                self.emit(BusEvent::Received(b1));
+                self.buffer.push_back((EbusData::PureByte(b1), at));
             }
         }
+        if self.buffer.len() > self.buffer_limit {
+            let excess = self.buffer.len() - self.buffer_limit;
+            warn!("Protocol buffer full, dropping {} bytes", excess);
+            self.buffer.drain(..excess);
+            self.stats.dropped += excess as u64;
+        }
         self.parse_protocol_buffer();
     }
 
     fn parse_protocol_buffer(&mut self) {
-        logDln(format!("\nparse_protocol_buffer, buffer len: {}\n", self.buffer.len()));
-        loop {
-            // pop first element from buffer
-            let b = match self.buffer.pop_front() {
-                Some(b) => b,
-                None => break
-            };
+        trace!("parse_protocol_buffer, buffer len: {}", self.buffer.len());
+        // pop first element from buffer
+        while let Some((b, at)) = self.buffer.pop_front() {
+            self.byte_at = at;
             // deencapsulate data byte
             let byte = match b {
                 EbusData::PureByte(b) => b,
                 EbusData::EnhancedProtocol(_cmd, data) => data
             };
-            logD(format!("({:02x})", byte));
+            trace!("({:02x})", byte);
+
+            // SYN is always escaped inside of a frame, so a plain one means the frame
+            // was cut off (eg. collision during arbitration) and the next one starts
+            if byte == SYN && matches!(self.state, EbusParserState::WaitingForDest | EbusParserState::WaitingForPB |
+                EbusParserState::WaitingForSB | EbusParserState::WaitingForLen | EbusParserState::WaitingForData |
+                EbusParserState::WaitingForCRC) {
+                self.stats.malformed += 1;
+                self.frame_error(FrameError::Interrupted);
+                self.clear();
+                self.state = EbusParserState::WaitingForSrc;
+                continue;
+            }
+
+            // keep frame bytes as received
+            match self.state {
+                EbusParserState::WaitingForSrc if byte == SYN => {}
+                EbusParserState::WaitingForResponse if byte == SYN => {}
+                EbusParserState::WaitingForSrc | EbusParserState::WaitingForDest | EbusParserState::WaitingForPB |
+                EbusParserState::WaitingForSB | EbusParserState::WaitingForLen => self.request.raw.push(byte),
+                EbusParserState::WaitingForData | EbusParserState::WaitingForCRC if !self.got_response => self.request.raw.push(byte),
+                EbusParserState::WaitingForData | EbusParserState::WaitingForCRC | EbusParserState::WaitingForResponse => self.response.raw.push(byte),
+                EbusParserState::WaitingForSYN | EbusParserState::WaitingForACK => {}
+            }
+
+            // de-escape data and CRC bytes: A9 00 -> A9, A9 01 -> AA
+            let byte = if matches!(self.state, EbusParserState::WaitingForData | EbusParserState::WaitingForCRC) {
+                if self.escape_pending {
+                    self.escape_pending = false;
+                    match byte {
+                        0x00 => ESC,
+                        0x01 => SYN,
+                        _ => {
+                            self.frame_error(FrameError::InvalidEscape(byte));
+                            self.clear();
+                            continue;
+                        }
+                    }
+                } else if byte == ESC {
+                    self.escape_pending = true;
+                    continue;
+                } else {
+                    byte
+                }
+            } else {
+                byte
+            };
         
             match &self.state {
                 EbusParserState::WaitingForSYN => {
@@ -312,9 +960,16 @@ impl EbusParser {
                 }
                 EbusParserState::WaitingForSrc => {
                     // print!("W1");
-                    if byte != SYN {
+                    if byte != SYN && !is_master(byte) {
+                        // noise or leftover of lost arbitration, skip to next SYN
+                        self.request.src = byte;
+                        self.stats.malformed += 1;
+                        self.frame_error(FrameError::InvalidSource(byte));
+                        self.clear();
+                    } else if byte != SYN {
                         // print!("GS ");
                         self.request.src = byte;
+                        self.request.gap = self.last_frame.map(|t| at.saturating_duration_since(t));
                         self.state = EbusParserState::WaitingForDest;
                     }
                 }
@@ -323,6 +978,7 @@ impl EbusParser {
                     self.request.dest = byte;
                     if self.request.dest == BROADCAST {
                         self.got_broadcast = true;
+                        self.stats.broadcasts += 1;
                     }
                     self.state = EbusParserState::WaitingForPB;
                 }
@@ -338,8 +994,9 @@ impl EbusParser {
                 }
                 EbusParserState::WaitingForLen => {
                     // print!("LN ");
-                    if byte > 0x10 {
-                        // errorneous data - LEN cannot exceed 16 bytes, drop this frame and wait for next one
+                    if byte > self.max_data_len {
+                        // errorneous data - LEN cannot exceed max_data_len, drop this frame and wait for next one
+                        self.frame_error(FrameError::TooLong(byte));
                         self.clear()
                     } else {
                         if self.got_response {
@@ -347,8 +1004,9 @@ impl EbusParser {
                         } else {
                             self.request.len = byte;
                         }
-                        self.incoming_data_len = byte as i8;   
-                        self.state = EbusParserState::WaitingForData;
+                        self.incoming_data_len = byte;   
+                        // zero-length telegram is followed by its CRC right away
+                        self.state = if byte == 0 { EbusParserState::WaitingForCRC } else { EbusParserState::WaitingForData };
                     }
                 }
                 EbusParserState::WaitingForData => {
@@ -369,28 +1027,35 @@ impl EbusParser {
                 }
                 EbusParserState::WaitingForCRC => {
                     // print!("CRC:");
-                    if self.got_response {
+                    let crc = if self.got_response {
                         self.response.crc = byte;
-                        let crc = self.response.calc_crc8();
-                        if crc == byte {
-                            // print!("CRC OK");
-                        } else {
-                            // print!("CRC ERR");
-                            // CRC error - drop this frame and wait for next one
-                            self.clear();
-                        }
+                        self.response.computed_crc = self.response.calc_crc8(self.crc_mode);
+                        self.response.computed_crc
                     } else {
                         self.request.crc = byte;
-                        let crc = self.request.calc_crc8();
-                        if crc == byte {
-                            // print!("CRC OK");
+                        self.request.computed_crc = self.request.calc_crc8(self.crc_mode);
+                        self.request.computed_crc
+                    };
+                    if crc == byte {
+                        // print!("CRC OK");
+                        self.state = EbusParserState::WaitingForACK;
+                    } else {
+                        // print!("CRC ERR");
+                        // CRC error - drop this frame and wait for next one
+                        self.stats.crc_errors += 1;
+                        self.frame_error(FrameError::Crc { expected: crc, received: byte });
+                        if let Some(cb) = &mut self.crc_error_callback {
+                            let resp = if self.got_response { Some(&self.response) } else { None };
+                            cb(&self.request, resp);
+                        }
+                        if self.deliver_crc_errors {
+                            // nothing after a bad CRC can be trusted, frame ends here
+                            self.state = EbusParserState::WaitingForSYN;
+                            self.process();
                         } else {
-                            // print!("CRC ERR");
-                            // CRC error - drop this frame and wait for next one
                             self.clear();
                         }
                     }
-                    self.state = EbusParserState::WaitingForACK;
                 }
                 EbusParserState::WaitingForACK => {
                     // print!("WA:");
@@ -403,11 +1068,14 @@ impl EbusParser {
                             self.state = EbusParserState::WaitingForSYN;
                             self.process();
                         } else {
+                            self.ack_at = Some(at);
                             self.state = EbusParserState::WaitingForResponse;
                         }
                     } else if byte == NACK {
                         // print!("NACK");
                         // no ACK - devices need to retransmit, drop this frame
+                        self.stats.nacks += 1;
+                        self.frame_error(FrameError::Nack);
                         self.state = EbusParserState::WaitingForSYN;
                         self.clear();
                     } else if byte == SYN {
@@ -415,6 +1083,12 @@ impl EbusParser {
                         // SYN received, if broadcast - request can be not ACKed
                         if self.got_broadcast {
                             self.process();
+                        } else {
+                            // request or response nobody acknowledged, dropped with
+                            // its data so that it can't leak into the next frame
+                            self.stats.malformed += 1;
+                            self.frame_error(FrameError::Interrupted);
+                            self.clear();
                         }
                         self.state = EbusParserState::WaitingForSrc;
                     } else {
@@ -426,18 +1100,30 @@ impl EbusParser {
                 }
                 EbusParserState::WaitingForResponse => {
                     // print!("WRS");
-                    if byte == SYN {
+                    let late = self.ack_at.is_some_and(|t| at.saturating_duration_since(t) > RESPONSE_WAIT_MAX);
+                    if byte == SYN || late {
                         // print!(":NRS ");
-                        // no response - process received frame
-                        self.state = EbusParserState::WaitingForSYN;
+                        // no response - process received frame, the SYN may already
+                        // be followed by the next frame
+                        if late {
+                            debug!("No response within {:?}, byte {:02X} dropped", RESPONSE_WAIT_MAX, byte);
+                        }
                         self.process();
+                        self.state = if byte == SYN { EbusParserState::WaitingForSrc } else { EbusParserState::WaitingForSYN };
                     } else {
                         // we've got response - wait for response data
                         // print!(":RS ");
                         self.got_response = true;
+                        self.response.latency = self.ack_at.map(|t| at.saturating_duration_since(t));
                         self.response.len = byte;
-                        self.incoming_data_len = byte as i8;
-                        self.state = EbusParserState::WaitingForData;
+                        if byte > self.max_data_len {
+                            // same limit as request LEN, drop frame and wait for next one
+                            self.frame_error(FrameError::TooLong(byte));
+                            self.clear();
+                            continue;
+                        }
+                        self.incoming_data_len = byte;
+                        self.state = if byte == 0 { EbusParserState::WaitingForCRC } else { EbusParserState::WaitingForData };
                     }
                 }            
             }
@@ -445,18 +1131,43 @@ impl EbusParser {
     }
 
     fn process(&mut self) {
-        logD("\nIncoming pkt: ".to_string());
+        debug!("Incoming pkt");
         self.process_frame();
         self.got_response = false;
         self.ack_received = false;
+        self.got_broadcast = false;
+        self.ack_at = None;
         self.response.clear();
         self.request.clear();
     }
 
     fn process_frame(&mut self) {
-        logIln(format!("{}", self.request));
+        self.stats.frames += 1;
+        // wall clock time of byte read, earlier than now when parsing lags behind
+        let behind = self.byte_at.elapsed();
+        self.request.timestamp = SystemTime::now().checked_sub(behind).unwrap_or_else(SystemTime::now);
+        self.last_frame = Some(self.byte_at);
+        // slave may also not answer, then only its address tells the kind
+        self.request.kind = if self.got_broadcast {
+            TelegramKind::Broadcast
+        } else if self.got_response {
+            TelegramKind::MasterSlave
+        } else {
+            TelegramKind::from_dest(self.request.dest)
+        };
+        // NACKed requests are dropped, so false means no ACK was seen at all
+        // (eg. frame with CRC error)
+        self.request.acked = if self.got_broadcast { None } else { Some(self.ack_received) };
+        info!("{}", self.request);
         if self.got_response {
-            logIln(format!(" `-:> {}", self.response));
+            info!(" `-:> {}", self.response);
+        }
+
+        let resp = if self.got_response { Some(&self.response) } else { None };
+        if let Some(capture) = &mut self.capture {
+            if let Err(e) = capture.write_frame(&self.request, resp) {
+                warn!("Failed to write capture log: {}", e);
+            }
         }
 
         // do callback
@@ -468,3 +1179,391 @@ impl EbusParser {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    // (src, dest, pbsb, request data, response data) of a delivered frame
+    type Frame = (u8, u8, u16, Vec<u8>, Option<Vec<u8>>);
+
+    fn frame(req: &EbusRequest, resp: Option<&EbusResponse>) -> Frame {
+        (req.src(), req.dest(), req.pbsb(), req.data().clone(), resp.map(|r| r.data().clone()))
+    }
+
+    // raw protocol parser collecting delivered frames
+    fn parser(builder: EbusParserBuilder) -> (EbusParser, Arc<Mutex<Vec<Frame>>>) {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let sink = frames.clone();
+        let parser = builder.build(move |req, resp| sink.lock().unwrap().push(frame(req, resp)));
+        (parser, frames)
+    }
+
+    // frames delivered for bus bytes fed at once and byte by byte, which
+    // have to be the same
+    fn parse_with(builder: impl Fn() -> EbusParserBuilder, bytes: &[u8]) -> Vec<Frame> {
+        let (mut whole, whole_frames) = parser(builder());
+        whole.feed(bytes, bytes.len());
+        let (mut single, single_frames) = parser(builder());
+        for b in bytes {
+            single.feed(&[*b], 1);
+        }
+        let frames = whole_frames.lock().unwrap().clone();
+        assert_eq!(frames, *single_frames.lock().unwrap());
+        frames
+    }
+
+    fn parse(bytes: &[u8]) -> Vec<Frame> {
+        parse_with(|| EbusParserBuilder::new().protocol(EbusProtocol::Raw), bytes)
+    }
+
+    // bus bytes framed by SYN, ACK of request (and response) included
+    fn master_slave(req: &EbusRequest, resp: &EbusResponse) -> Vec<u8> {
+        let mut bytes = vec![SYN];
+        bytes.extend(req.raw());
+        bytes.push(ACK);
+        bytes.extend(resp.raw());
+        bytes.push(ACK);
+        bytes.push(SYN);
+        bytes
+    }
+
+    // bus bytes as an enhanced protocol adapter sends them, bytes >= 0x80 as
+    // RECEIVED command pairs
+    fn enhanced(bytes: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for b in bytes {
+            if *b < 0x80 {
+                encoded.push(*b);
+            } else {
+                let (b1, b2) = encode_enhproto_tuple(EnhProtoResponse::Received as u8, *b);
+                encoded.extend([b1, b2]);
+            }
+        }
+        encoded
+    }
+
+    fn broadcast(req: &EbusRequest) -> Vec<u8> {
+        let mut bytes = vec![SYN];
+        bytes.extend(req.raw());
+        bytes.push(SYN);
+        bytes
+    }
+
+    #[test]
+    fn master_slave_exchange() {
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0x29, 0x00]);
+        let resp = EbusResponse::new(&[0x50, 0x03, 0x01]);
+        assert_eq!(parse(&master_slave(&req, &resp)), vec![frame(&req, Some(&resp))]);
+    }
+
+    #[test]
+    fn broadcast_is_delivered_without_ack() {
+        let req = EbusRequest::new(0x10, BROADCAST, 0x0700, &[0x80, 0x0A, 0x30, 0x15, 0x08, 0x14, 0x10, 0x03, 0x26]);
+        assert_eq!(parse(&broadcast(&req)), vec![frame(&req, None)]);
+    }
+
+    #[test]
+    fn nacked_request_is_dropped() {
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0x29, 0x00]);
+        let mut bytes = vec![SYN];
+        bytes.extend(req.raw());
+        bytes.extend([NACK, SYN]);
+        assert!(parse(&bytes).is_empty());
+    }
+
+    #[test]
+    fn unacked_request_does_not_corrupt_next_frame() {
+        let req = EbusRequest::new(0x10, 0x03, 0xB505, &[0x01, 0x02]);
+        let next = EbusRequest::new(0x10, BROADCAST, 0x0700, &[0x05]);
+        let mut bytes = vec![SYN];
+        bytes.extend(req.raw());
+        bytes.extend(broadcast(&next));
+        assert_eq!(parse(&bytes), vec![frame(&next, None)]);
+    }
+
+    #[test]
+    fn unacked_response_does_not_corrupt_next_frame() {
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D]);
+        let resp = EbusResponse::new(&[0x01, 0x02]);
+        let next = EbusRequest::new(0x10, BROADCAST, 0x0700, &[0x05]);
+        let mut bytes = vec![SYN];
+        bytes.extend(req.raw());
+        bytes.push(ACK);
+        bytes.extend(resp.raw());
+        bytes.extend(broadcast(&next));
+        assert_eq!(parse(&bytes), vec![frame(&next, None)]);
+    }
+
+    #[test]
+    fn syn_at_every_position_resyncs() {
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0x29]);
+        let resp = EbusResponse::new(&[0x50, 0x03]);
+        let next = EbusRequest::new(0x30, 0x15, 0xB504, &[0x01]);
+        let next_resp = EbusResponse::new(&[0x02]);
+        // without leading SYN and trailing one
+        let exchange = &master_slave(&req, &resp)[1..];
+        let exchange = &exchange[..exchange.len() - 1];
+        let answered = req.raw().len() + 1;
+        for cut in 0..=exchange.len() {
+            let mut bytes = vec![SYN];
+            bytes.extend(&exchange[..cut]);
+            bytes.extend(master_slave(&next, &next_resp));
+            // cut off after ACK of request is a request left unanswered,
+            // after ACK of response the complete exchange
+            let mut expected = match cut {
+                c if c == answered => vec![frame(&req, None)],
+                c if c == exchange.len() => vec![frame(&req, Some(&resp))],
+                _ => Vec::new(),
+            };
+            expected.push(frame(&next, Some(&next_resp)));
+            assert_eq!(parse(&bytes), expected, "SYN after {} byte(s)", cut);
+        }
+    }
+
+    #[test]
+    fn crc_error_is_dropped() {
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0x29, 0x00]);
+        let resp = EbusResponse::new(&[0x50, 0x03, 0x01]);
+        let mut bytes = master_slave(&req, &resp);
+        // request crc, before its ACK
+        let crc = req.raw().len();
+        bytes[crc] ^= 0x01;
+        assert!(parse(&bytes).is_empty());
+        // frame after the broken one is not affected
+        bytes.extend(&master_slave(&req, &resp)[1..]);
+        assert_eq!(parse(&bytes), vec![frame(&req, Some(&resp))]);
+    }
+
+    #[test]
+    fn hex_accessors_are_zero_padded() {
+        let req = EbusRequest::new(0x03, 0x0F, 0x0704, &[0x0A]);
+        assert_eq!(req.src_hex(), "03");
+        assert_eq!(req.dest_hex(), "0F");
+        assert_eq!(req.pbsb_hex(), "0704");
+        assert_eq!(req.len_hex(), "01");
+        assert_eq!(req.data_hex(), "0A");
+        assert_eq!(EbusResponse::new(&[0x0B]).len_hex(), "01");
+    }
+
+    #[test]
+    fn unknown_enhanced_commands_are_skipped() {
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0x29, 0x00]);
+        let resp = EbusResponse::new(&[0x50, 0x03, 0x01]);
+        let mut bytes = Vec::new();
+        for b in master_slave(&req, &resp) {
+            bytes.extend(enhanced(&[b]));
+            // command codes 4..=9 and 0x0D..=0x0F are not defined
+            let (b1, b2) = encode_enhproto_tuple(4 + b % 6, b);
+            bytes.extend([b1, b2]);
+        }
+        assert_eq!(parse_with(EbusParserBuilder::new, &bytes), vec![frame(&req, Some(&resp))]);
+    }
+
+    #[test]
+    fn enhanced_noise_does_not_break_parser() {
+        // every command pair, then pseudo random bytes >= 0x80
+        let mut pairs = Vec::new();
+        for b1 in 0xC0..=0xFF {
+            for b2 in 0x80..=0xBF {
+                pairs.extend([b1, b2]);
+            }
+        }
+        let mut x: u32 = 0x2545F491;
+        let noise = (0..4096).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8 | 0x80
+        }).collect();
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0x29, 0x00]);
+        let resp = EbusResponse::new(&[0x50, 0x03, 0x01]);
+        for bytes in [pairs, noise] {
+            let (mut parser, frames) = parser(EbusParserBuilder::new());
+            parser.feed(&bytes, bytes.len());
+            assert!(parser.stats().malformed > 0);
+            // back in sync with the next frame
+            frames.lock().unwrap().clear();
+            let next = enhanced(&master_slave(&req, &resp));
+            parser.feed(&next, next.len());
+            assert_eq!(*frames.lock().unwrap(), vec![frame(&req, Some(&resp))]);
+        }
+    }
+
+    #[test]
+    fn enhproto_tuple_roundtrip() {
+        for cmd in 0..=0x0F {
+            for data in 0..=0xFF {
+                let (b1, b2) = encode_enhproto_tuple(cmd, data);
+                assert_eq!((b1 & 0xC0, b2 & 0xC0), (0xC0, 0x80), "{:02X} {:02X}", cmd, data);
+                assert_eq!(decode_enhproto_tuple(b1, b2), (cmd, data));
+            }
+        }
+    }
+
+    #[test]
+    fn escaped_bytes_in_data_and_crc() {
+        let req = EbusRequest::new(0x10, 0xFE, 0xB516, &[0x01, SYN, ESC, 0x02]);
+        assert_eq!(&req.raw()[5..11], &[0x01, ESC, 0x01, ESC, 0x00, 0x02]);
+        let resp = EbusResponse::new(&[SYN, ESC]);
+        let exchange = EbusRequest::new(0x10, 0x08, 0xB509, &[ESC, SYN]);
+        assert_eq!(parse(&broadcast(&req)), vec![frame(&req, None)]);
+        assert_eq!(parse(&master_slave(&exchange, &resp)), vec![frame(&exchange, Some(&resp))]);
+        // crc escaped as well, ending in A9 01 / A9 00
+        for crc in [SYN, ESC] {
+            let req = (0..=0xFF).map(|b| EbusRequest::new(0x10, 0xFE, 0x0700, &[b])).find(|r| r.crc() == crc).unwrap();
+            assert_eq!(req.raw().last(), Some(&(crc - ESC)));
+            assert_eq!(parse(&broadcast(&req)), vec![frame(&req, None)]);
+        }
+    }
+
+    #[test]
+    fn frame_delivered_as_soon_as_complete() {
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0x29, 0x00]);
+        let resp = EbusResponse::new(&[0x50, 0x03, 0x01]);
+        let bytes = enhanced(&master_slave(&req, &resp));
+        let (mut parser, frames) = parser(EbusParserBuilder::new());
+        // nothing follows the final ACK, no flush needed
+        let (exchange, syn) = bytes.split_at(bytes.len() - 2);
+        for b in exchange {
+            assert!(frames.lock().unwrap().is_empty());
+            parser.feed(&[*b], 1);
+        }
+        assert_eq!(*frames.lock().unwrap(), vec![frame(&req, Some(&resp))]);
+        parser.feed(syn, syn.len());
+        assert_eq!(frames.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn enhanced_frame_split_inside_pair() {
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0xC5, 0x00]);
+        let resp = EbusResponse::new(&[0xA0, 0x03, 0xFF]);
+        let bytes = enhanced(&master_slave(&req, &resp));
+        // every split, odd ones ending inside of a pair (eg. after the first
+        // byte of SYN) included
+        for at in 0..=bytes.len() {
+            let (mut parser, frames) = parser(EbusParserBuilder::new());
+            parser.feed(&bytes[..at], at);
+            parser.feed(&bytes[at..], bytes.len() - at);
+            assert_eq!(*frames.lock().unwrap(), vec![frame(&req, Some(&resp))], "split at {}", at);
+            assert_eq!(parser.stats().malformed, 0);
+        }
+    }
+
+    #[test]
+    fn raw_protocol_keeps_high_bytes() {
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0xC3, 0xFF, 0x80]);
+        let resp = EbusResponse::new(&[0xC0, 0x9A]);
+        let bytes = master_slave(&req, &resp);
+        assert_eq!(parse(&bytes), vec![frame(&req, Some(&resp))]);
+        // taken as command pairs by the enhanced protocol
+        assert!(parse_with(EbusParserBuilder::new, &bytes).is_empty());
+    }
+
+    #[test]
+    fn crc_error_callback_gets_bad_frame() {
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0x29, 0x00]);
+        let resp = EbusResponse::new(&[0x50, 0x03, 0x01]);
+        let mut bytes = master_slave(&req, &resp);
+        // response crc, before its ACK
+        let crc = bytes.len() - 3;
+        bytes[crc] ^= 0x01;
+        for deliver in [false, true] {
+            let bad = Arc::new(Mutex::new(Vec::new()));
+            let sink = bad.clone();
+            let builder = EbusParserBuilder::new().protocol(EbusProtocol::Raw).deliver_crc_errors(deliver)
+                .on_crc_error(move |req, resp| sink.lock().unwrap().push((frame(req, resp), resp.unwrap().crc_valid())));
+            let (mut parser, frames) = parser(builder);
+            parser.feed(&bytes, bytes.len());
+            assert_eq!(*bad.lock().unwrap(), vec![(frame(&req, Some(&resp)), false)]);
+            assert_eq!(frames.lock().unwrap().len(), deliver as usize);
+        }
+    }
+
+    #[test]
+    fn flush_threshold_holds_bytes_back() {
+        let req = EbusRequest::new(0x10, BROADCAST, 0x0700, &[0x01]);
+        let bytes = broadcast(&req);
+        let (mut parser, frames) = parser(EbusParserBuilder::new().protocol(EbusProtocol::Raw).flush_threshold(64));
+        parser.feed(&bytes, bytes.len());
+        assert!(frames.lock().unwrap().is_empty());
+        parser.flush();
+        assert_eq!(*frames.lock().unwrap(), vec![frame(&req, None)]);
+        // parsed on its own once threshold is reached
+        let many = [bytes[1..].repeat(8), vec![SYN]].concat();
+        parser.feed(&many, many.len());
+        assert_eq!(frames.lock().unwrap().len(), 9);
+    }
+
+    #[test]
+    fn timing_taken_from_read_time() {
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0x29, 0x00]);
+        let resp = EbusResponse::new(&[0x50, 0x03, 0x01]);
+        let bytes = master_slave(&req, &resp);
+        let timing = Arc::new(Mutex::new(Vec::new()));
+        let sink = timing.clone();
+        let mut parser = EbusParserBuilder::new().protocol(EbusProtocol::Raw)
+            .build(move |req, resp| sink.lock().unwrap().push((req.gap(), resp.and_then(|r| r.latency()))));
+        // request and its ACK read at once, response 20ms later
+        let ack = req.raw().len() + 2;
+        let t0 = Instant::now();
+        parser.feed_at(&bytes[..ack], ack, t0);
+        parser.feed_at(&bytes[ack..], bytes.len() - ack, t0 + Duration::from_millis(20));
+        // next frame 100ms after the first one ended
+        parser.feed_at(&bytes[1..], bytes.len() - 1, t0 + Duration::from_millis(120));
+        assert_eq!(*timing.lock().unwrap(), vec![
+            (None, Some(Duration::from_millis(20))),
+            (Some(Duration::from_millis(100)), Some(Duration::ZERO))
+        ]);
+    }
+
+    #[test]
+    fn response_len_over_max_is_dropped() {
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0x29, 0x00]);
+        let long = EbusResponse::new(&[0x01; 17]);
+        let resp = EbusResponse::new(&[0x50, 0x03, 0x01]);
+        let bytes = [master_slave(&req, &long), master_slave(&req, &resp)[1..].to_vec()].concat();
+        assert_eq!(parse(&bytes), vec![frame(&req, Some(&resp))]);
+        // fine when max_data_len allows it
+        let frames = parse_with(|| EbusParserBuilder::new().protocol(EbusProtocol::Raw).max_data_len(17), &bytes);
+        assert_eq!(frames, vec![frame(&req, Some(&long)), frame(&req, Some(&resp))]);
+    }
+
+    #[test]
+    fn error_callback_reports_dropped_frames() {
+        let req = EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0x29, 0x00]);
+        let resp = EbusResponse::new(&[0x50, 0x03, 0x01]);
+        let mut nacked = vec![SYN];
+        nacked.extend(req.raw());
+        nacked.push(NACK);
+        let mut bad_crc = master_slave(&req, &resp);
+        bad_crc[req.raw().len()] ^= 0x01;
+        let long_request = broadcast(&EbusRequest::new(0x10, BROADCAST, 0x0700, &[0x00; 17]));
+        let long_response = master_slave(&req, &EbusResponse::new(&[0x00; 17]));
+        let bytes = [nacked, bad_crc, long_request, long_response].concat();
+
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let sink = errors.clone();
+        let builder = EbusParserBuilder::new().protocol(EbusProtocol::Raw)
+            .on_error(move |error, req, resp| sink.lock().unwrap().push((*error, req.pbsb(), resp.map(|r| r.len))));
+        let (mut parser, _) = parser(builder);
+        parser.feed(&bytes, bytes.len());
+        assert_eq!(*errors.lock().unwrap(), vec![
+            (FrameError::Nack, 0xB509, None),
+            (FrameError::Crc { expected: req.crc(), received: req.crc() ^ 0x01 }, 0xB509, None),
+            (FrameError::TooLong(17), 0x0700, None),
+            (FrameError::TooLong(17), 0xB509, Some(17))
+        ]);
+    }
+
+    #[test]
+    fn parser_can_move_to_another_thread() {
+        let req = EbusRequest::new(0x10, BROADCAST, 0x0700, &[0x01]);
+        let bytes = broadcast(&req);
+        let (mut parser, frames) = parser(EbusParserBuilder::new().protocol(EbusProtocol::Raw).on_error(|_, _, _| {}));
+        std::thread::spawn(move || parser.feed(&bytes, bytes.len())).join().unwrap();
+        assert_eq!(*frames.lock().unwrap(), vec![frame(&req, None)]);
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use log::debug;
+
+use super::parser::{encode_enhproto_tuple, escape, EbusRequest, EbusResponse, EnhProtoRequest, ACK, BROADCAST, ESC, NACK, SYN};
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
+
+
+// enhanced protocol responses of the adapter, forwarded by EbusParser
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusEvent {
+    Received(u8),
+    Started(u8),
+    Failed(u8),
+    ErrorEbus(u8),
+    ErrorHost(u8),
+}
+
+// reason why a telegram was not sent or not accepted
+#[derive(Debug)]
+pub enum SendError {
+    // writing to the interface failed (or parser feeding events is gone)
+    Io(io::Error),
+    // no answer from adapter or bus within RESPONSE_TIMEOUT
+    Timeout,
+    // another master won arbitration (its address)
+    ArbitrationLost(u8),
+    // byte echoed by the bus differs from the one sent
+    Collision { sent: u8, received: u8 },
+    // destination refused request
+    Nack,
+    // destination answered with neither ACK nor NACK
+    NoAck(u8),
+    // slave response failed CRC check (and was NACKed)
+    ResponseCrc { expected: u8, received: u8 },
+    // adapter reported error on bus side (ERROR_EBUS) / host side (ERROR_HOST)
+    AdapterEbus(u8),
+    AdapterHost(u8),
+    // adapter response not fitting current step
+    Unexpected(BusEvent),
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            SendError::Io(e) => write!(f, "I/O error: {}", e),
+            SendError::Timeout => write!(f, "no response from bus"),
+            SendError::ArbitrationLost(winner) => write!(f, "arbitration lost to {:02X}", winner),
+            SendError::Collision { sent, received } => write!(f, "bus collision, sent {:02X} got {:02X}", sent, received),
+            SendError::Nack => write!(f, "NACK"),
+            SendError::NoAck(b) => write!(f, "expected ACK/NACK, got {:02X}", b),
+            SendError::ResponseCrc { expected, received } => write!(f, "response CRC error (expected {:02X}, received {:02X})", expected, received),
+            SendError::AdapterEbus(e) => write!(f, "adapter ebus error {:02X}", e),
+            SendError::AdapterHost(e) => write!(f, "adapter host error {:02X}", e),
+            SendError::Unexpected(event) => write!(f, "unexpected adapter response {:?}", event),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+impl From<io::Error> for SendError {
+    fn from(e: io::Error) -> Self {
+        SendError::Io(e)
+    }
+}
+
+// master addresses have both nibbles in 0, 1, 3, 7, F
+pub fn is_master(addr: u8) -> bool {
+    let valid = |n: u8| matches!(n, 0x0 | 0x1 | 0x3 | 0x7 | 0xF);
+    valid(addr >> 4) && valid(addr & 0x0F)
+}
+
+pub struct EbusSender {
+    writer: Box<dyn Write + Send>,
+    events: Receiver<BusEvent>,
+}
+
+impl EbusSender {
+    // events must come from the parser reading the same interface (EbusParser::set_event_sink)
+    pub fn new(writer: Box<dyn Write + Send>, events: Receiver<BusEvent>) -> EbusSender {
+        EbusSender { writer, events }
+    }
+
+    // replaces writer after interface reconnect
+    pub fn set_writer(&mut self, writer: Box<dyn Write + Send>) {
+        self.writer = writer;
+    }
+
+    // sends request telegram onto the bus, Ok once acknowledged by destination
+    // (broadcasts are never acknowledged), with the response of a slave
+    pub fn send_request(&mut self, src: u8, dest: u8, pbsb: u16, data: &[u8]) -> Result<Option<EbusResponse>, SendError> {
+        let telegram = escape(&EbusRequest::new(src, dest, pbsb, data).to_bytes());
+        debug!("Sending telegram {:02X?}", telegram);
+
+        // drop anything received before we've started
+        self.discard_events();
+
+        // arbitration with our source address
+        self.write_cmd(EnhProtoRequest::Start, src)?;
+        match self.wait_event()? {
+            BusEvent::Started(_) => {}
+            BusEvent::Failed(winner) => return Err(SendError::ArbitrationLost(winner)),
+            BusEvent::ErrorEbus(e) => return Err(SendError::AdapterEbus(e)),
+            BusEvent::ErrorHost(e) => return Err(SendError::AdapterHost(e)),
+            e => return Err(SendError::Unexpected(e)),
+        }
+
+        // rest of telegram, each byte is echoed back by adapter
+        for b in &telegram[1..] {
+            self.write_cmd(EnhProtoRequest::Send, *b)?;
+        }
+        for b in &telegram[1..] {
+            let echo = self.wait_byte()?;
+            if echo != *b {
+                self.write_cmd(EnhProtoRequest::Send, SYN)?;
+                return Err(SendError::Collision { sent: *b, received: echo });
+            }
+        }
+
+        if dest == BROADCAST {
+            self.write_cmd(EnhProtoRequest::Send, SYN)?;
+            return Ok(None);
+        }
+
+        match self.wait_byte()? {
+            ACK => {}
+            b => {
+                self.write_cmd(EnhProtoRequest::Send, SYN)?;
+                return Err(if b == NACK { SendError::Nack } else { SendError::NoAck(b) });
+            }
+        }
+
+        // slave answers with response, which we have to acknowledge
+        let mut response = None;
+        if !is_master(dest) {
+            let len = self.wait_byte()?;
+            // data + crc, escape sequences don't count into len
+            let mut bytes = Vec::new();
+            while bytes.len() < len as usize + 1 {
+                let b = match self.wait_byte()? {
+                    ESC if self.wait_byte()? == 0x01 => SYN,
+                    ESC => ESC,
+                    b => b,
+                };
+                bytes.push(b);
+            }
+            let crc = bytes.pop().unwrap();
+            let resp = EbusResponse::new(&bytes);
+            if resp.crc() != crc {
+                self.write_cmd(EnhProtoRequest::Send, NACK)?;
+                self.write_cmd(EnhProtoRequest::Send, SYN)?;
+                return Err(SendError::ResponseCrc { expected: resp.crc(), received: crc });
+            }
+            self.write_cmd(EnhProtoRequest::Send, ACK)?;
+            response = Some(resp);
+        }
+        self.write_cmd(EnhProtoRequest::Send, SYN)?;
+        Ok(response)
+    }
+
+    // drops bus events queued since the last send, they pile up otherwise
+    // while nothing is sent
+    pub fn discard_events(&mut self) {
+        while self.events.try_recv().is_ok() {}
+    }
+
+    fn write_cmd(&mut self, cmd: EnhProtoRequest, data: u8) -> io::Result<()> {
+        let (b1, b2) = encode_enhproto_tuple(cmd as u8, data);
+        self.writer.write_all(&[b1, b2])?;
+        self.writer.flush()
+    }
+
+    fn wait_event(&mut self) -> Result<BusEvent, SendError> {
+        match self.events.recv_timeout(RESPONSE_TIMEOUT) {
+            Ok(event) => Ok(event),
+            Err(RecvTimeoutError::Timeout) => Err(SendError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(io::ErrorKind::NotConnected, "parser is gone").into()),
+        }
+    }
+
+    // waits for next byte seen on the bus, adapter errors are reported as failure
+    fn wait_byte(&mut self) -> Result<u8, SendError> {
+        loop {
+            match self.wait_event()? {
+                BusEvent::Received(b) => return Ok(b),
+                BusEvent::ErrorEbus(e) => return Err(SendError::AdapterEbus(e)),
+                BusEvent::ErrorHost(e) => return Err(SendError::AdapterHost(e)),
+                _ => continue,
+            }
+        }
+    }
+}
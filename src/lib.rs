@@ -0,0 +1,15 @@
+pub mod command;
+pub mod config;
+pub mod definitions;
+pub mod ebus;
+pub mod mapper;
+pub mod metrics;
+pub mod mqtt;
+pub mod receiver;
+pub mod replay;
+pub mod selftest;
+pub mod source;
+
+pub use ebus::parser::{EbusParser, EbusParserBuilder, EbusRequest, EbusResponse, TelegramKind};
+pub use ebus::sender::{EbusSender, SendError};
+pub use mapper::Mapper;
@@ -0,0 +1,64 @@
+use std::io::{IsTerminal, Write};
+
+use env_logger::Target;
+
+use log::{Level, LevelFilter};
+
+
+fn level_tag(level: Level) -> &'static str {
+    match level {
+        Level::Trace => "[T]",
+        Level::Debug => "[D]",
+        Level::Info => "[I]",
+        Level::Warn => "[W]",
+        Level::Error => "[E]"
+    }
+}
+
+// ANSI color of level tag, when logging to a terminal
+fn level_color(level: Level) -> &'static str {
+    match level {
+        Level::Trace => "90",
+        Level::Debug => "36",
+        Level::Info => "32",
+        Level::Warn => "33",
+        Level::Error => "31"
+    }
+}
+
+fn rust_log_set() -> bool {
+    std::env::var_os("RUST_LOG").is_some()
+}
+
+// RUST_LOG (eg. RUST_LOG=debug) takes precedence over log_level from config.json,
+// until config is loaded info level is used
+// log goes to stderr, stdout is kept for telegram output (eg. jsonl piped on),
+// level tags are colored when stderr is a terminal and NO_COLOR isn't set
+pub fn init() {
+    let color = std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    let mut builder = env_logger::Builder::new();
+    builder.target(Target::Stderr);
+    builder.format(move |buf, record| {
+        let tag = level_tag(record.level());
+        if color {
+            writeln!(buf, "\x1b[{}m{}\x1b[0m {}", level_color(record.level()), tag, record.args())
+        } else {
+            writeln!(buf, "{} {}", tag, record.args())
+        }
+    });
+    if rust_log_set() {
+        builder.parse_default_env();
+        builder.init();
+    } else {
+        // filtering is left to log::max_level, adjusted by set_level
+        builder.filter_level(LevelFilter::Trace);
+        builder.init();
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+pub fn set_level(level: LevelFilter) {
+    if !rust_log_set() {
+        log::set_max_level(level);
+    }
+}
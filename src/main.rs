@@ -1,230 +1,435 @@
 
-use std::fs::File;
-use std::io::{BufReader, Read};
-use std::net::TcpStream;
 use std::thread;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use ebus::parser::{EbusParser, EbusRequest, EbusResponse};
-use log::LogLevel;
+use log::{error, info, warn};
 
-use crate::log::*;
+use ebus_mqtt::{EbusSender, Mapper};
+use ebus_mqtt::command::Commands;
+use ebus_mqtt::mapper::{self, MapperOptions, OutputMode};
+use ebus_mqtt::metrics::Metrics;
+use ebus_mqtt::mqtt::{self, MqttPublisher};
+use ebus_mqtt::receiver::{Interruption, Receiver};
+use ebus_mqtt::replay;
+use ebus_mqtt::selftest;
+use ebus_mqtt::config::Config;
+use ebus_mqtt::definitions;
+use ebus_mqtt::ebus::address;
+use ebus_mqtt::source::{EbusSource, EbusStream};
 
-mod ebus;
-mod log;
+mod logger;
 
-const LOG_LEVEL : LogLevel = LogLevel::Info;
+const RECONNECT_DELAY_MIN : Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX : Duration = Duration::from_secs(30);
+// reconnect delay is slept in steps this long, checking `running` in between
+const RECONNECT_SLEEP_STEP : Duration = Duration::from_millis(100);
+const STATS_INTERVAL : Duration = Duration::from_secs(60);
+// bus connection down for this long is reported as degraded status
+const DEGRADED_AFTER : Duration = Duration::from_secs(30);
 
 
-/*
-    fn match_field() is matching value_hex with field_def
-
-    Field matching syntax:
-    *       - any value matches
-    ^<hex>  - value starts with <hex>
-    <hex>   - value matches exactly
- */
-
-fn match_field(value_hex:&str, field_def:&serde_json::Value) -> bool {
-    let field_pattern = field_def.as_str().unwrap();
-    let field_len = field_pattern.len();
-    // check for all-match
-    if field_pattern == "*" {
-        return true;
-    }
-    // check for starts-with
-    if field_pattern.starts_with("^") {
-        return value_hex.starts_with(&field_pattern[1..]);
+// tries to reconnect to ebus interface with exponential backoff,
+// returns None when stopped by `running` flag
+fn reconnect(source: &EbusSource, running: &AtomicBool, mqtt: Option<&MqttPublisher>) -> Option<Box<dyn EbusStream>> {
+    let mut delay = RECONNECT_DELAY_MIN;
+    let lost_at = Instant::now();
+    let mut degraded = false;
+    while running.load(Ordering::Relaxed) {
+        if let Some(mqtt) = mqtt.filter(|_| !degraded && lost_at.elapsed() >= DEGRADED_AFTER) {
+            mqtt.set_status(mqtt::STATUS_DEGRADED);
+            degraded = true;
+        }
+        warn!("Reconnecting to {} in {}s", source, delay.as_secs());
+        let wake_at = Instant::now() + delay;
+        while running.load(Ordering::Relaxed) && Instant::now() < wake_at {
+            thread::sleep(RECONNECT_SLEEP_STEP.min(wake_at.saturating_duration_since(Instant::now())));
+        }
+        if !running.load(Ordering::Relaxed) {
+            break;
+        }
+        match source.open() {
+            Ok(stream) => {
+                info!("Reconnected to {}", source);
+                if let Some(mqtt) = mqtt.filter(|_| degraded) {
+                    mqtt.set_status(mqtt::STATUS_ONLINE);
+                }
+                return Some(stream);
+            }
+            Err(e) => {
+                warn!("Reconnect to {} failed: {}", source, e);
+                delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+            }
+        }
     }
-    // check for exact match
-    for i in 0..field_len {
-        if field_pattern.chars().nth(i).unwrap() != '*' && field_pattern.chars().nth(i).unwrap() != value_hex.chars().nth(i).unwrap() {
-            return false;
+    None
+}
+
+// loads definition file and reports all structural problems,
+// before anything gets decoded
+fn load_definitions(filename: &str) -> Option<serde_json::Value> {
+    // Read the JSON contents of the file as untyped (gzipped when *.gz)
+    let def = match definitions::load(filename) {
+        Ok(def) => def,
+        Err(e) => {
+            error!("{}", e);
+            return None;
         }
+    };
+    let errors = mapper::validate_definitions(&def);
+    if !errors.is_empty() {
+        for e in &errors {
+            error!("{}: {}", filename, e);
+        }
+        error!("{}: {} definition error(s)", filename, errors.len());
+        return None;
     }
-    return true;
+    Some(def)
 }
 
+// validates definition file and prints summary of its messages,
+// returns false on any error
+fn check_definitions(filename: &str) -> bool {
+    let Some(def) = load_definitions(filename) else {
+        return false;
+    };
+    let mut messages = 0;
+    for circuit in def["circuits"].as_array().unwrap() {
+        for msg in circuit["messages"].as_array().unwrap() {
+            let request_match = &msg["request_match"];
+            let pbsb = match request_match.get("pbsb") {
+                Some(pbsb) => pbsb.to_string(),
+                None => format!("{} {}", request_match.get("pb").unwrap_or(&"*".into()), request_match.get("sb").unwrap_or(&"*".into())),
+            };
+            let fields = |map: &str| msg[map].as_array().map_or(0, |f| f.len());
+            println!("{}/{}: pbsb {}, {} request field(s), {} response field(s)", circuit["name"].as_str().unwrap(),
+                msg["comment"].as_str().unwrap_or(""), pbsb, fields("request_map"), fields("response_map"));
+            messages += 1;
+        }
+    }
+    println!("{}: OK, {} message(s)", filename, messages);
+    true
+}
 
-struct Mapper {
-    defs : serde_json::Value,
+// command line arguments
+#[derive(Default)]
+struct Args {
+    // capture file to feed through parser instead of live connection
+    replay: Option<String>,
+    // definition file to validate, without connecting anywhere
+    check: Option<String>,
+    // print loaded definitions and exit
+    list: bool,
+    // decode built-in frames against demo definitions and exit
+    selftest: bool,
+    // config file instead of ./config.json
+    config: Option<String>,
+    // definition files instead of ebus.definitions, --defs may be repeated
+    defs: Vec<String>,
 }
 
-impl Mapper {
-    fn new(defs : serde_json::Value) -> Mapper {
-        Mapper { defs }
+const USAGE: &str = "Usage: ebus_mqtt [--config <config file>] [--defs <definition file>]... [--replay <capture file>] [--check <definition file>] [--list] [--selftest]";
+const DEFAULT_CONFIG: &str = "./config.json";
+
+// file name argument of option, which has to exist
+fn existing_file(option: &str, filename: Option<String>) -> Result<String, String> {
+    let filename = filename.ok_or(format!("{} needs a file name", option))?;
+    if !std::path::Path::new(&filename).is_file() {
+        return Err(format!("{}: file {} does not exist", option, filename));
     }
+    Ok(filename)
+}
 
-    fn received_telegram(&mut self, req: &EbusRequest, resp: Option<&EbusResponse>) {
-        println!("Received telegram {}", req);
-        if let Some(r) = resp {
-            println!("    `-> Response: {}", r);
-        }
-        // iterate through all defined circuits
-        for circuit in self.defs["circuits"].as_array().unwrap() {
-            // println!("    Circuit: {}", circuit["name"].as_str().unwrap());
-
-            // iterate through possible circuit's messages
-            for msg in circuit["messages"].as_array().unwrap() {
-                // println!("        Message: {}", msg["comment"].as_str().unwrap());
-
-                // check if we've got matching request to message definition
-                if match_field(req.src_hex().as_str(), &msg["request_match"]["src"]) &&
-                   match_field(req.dest_hex().as_str(), &msg["request_match"]["dst"]) &&
-                   match_field(req.pbsb_hex().as_str(), &msg["request_match"]["pbsb"]) &&
-                   match_field(req.data_hex().as_str(), &msg["request_match"]["data"]) {
-                    // println!("            Matched request <OK>");
-
-                    // ok, let's initialize json object with parsed response data
-                    let mut result_js = serde_json::Map::new();
-
-                    // check if we've got "response_map" defined in msg
-                    let msgo = msg.as_object().unwrap();
-                    let mut field_map: Option<&serde_json::Value> = None;
-                    let mut data: Option<&Vec<u8>> = None;
-
-                    if msgo.contains_key("response_map") {
-                        // check if we've received a response
-                        if let Some(r) = resp {
-                            data = Some(r.data());
-                            field_map = Some(&msg["response_map"]);
-                        }
-                    }
-                    if msgo.contains_key("request_map") {
-                        data = Some(req.data());
-                        field_map = Some(&msg["request_map"]);
-                    }
-                    // check if we've got data and field_map defined
-                    if (data == None) || (field_map == None) {
-                        continue;
-                    }
-                    // parse data with field definitions 
-                    for field in field_map.unwrap().as_array().unwrap() {
-                        let bytes = data.unwrap();
-                        let field_name = field["field_name"].as_str().unwrap();
-                        let offset = field["field_offset"].as_u64().unwrap();
-                        let data_type = field["data_type"].as_str().unwrap();
-                        let factor = field["factor"].as_f64().unwrap();
-                        let unit = field["unit"].as_str().unwrap();
-                        println!{"                Field: {} @{:02x} t={} f={} [{}]", field_name, offset, data_type, factor, unit};
-                        match data_type {
-                            "u8" => {
-                                let val: u8 = bytes[offset as usize] as u8;
-                                if factor == 1.0 {
-                                    result_js.insert(field_name.to_string(), serde_json::Value::Number(serde_json::Number::from(val)));
-                                } else {
-                                    let value = val as f64 * factor;
-                                    result_js.insert(field_name.to_string(), serde_json::Value::Number(serde_json::Number::from_f64(value).unwrap()));
-                                }
-                            },
-                            "u16le" => {
-                                let val: u16 = (bytes[offset as usize] as u16) | ((bytes[offset as usize + 1] as u16) << 8);
-                                if factor == 1.0 {
-                                    result_js.insert(field_name.to_string(), serde_json::Value::Number(serde_json::Number::from(val)));
-                                } else {
-                                    let value = val as f64 * factor;
-                                    result_js.insert(field_name.to_string(), serde_json::Value::Number(serde_json::Number::from_f64(value).unwrap()));
-                                }
-                            },                  
-                            "u16he" => {
-                                let val: u16 = ((bytes[offset as usize] as u16) << 8) | (bytes[offset as usize + 1] as u16);
-                                if factor == 1.0 {
-                                    result_js.insert(field_name.to_string(), serde_json::Value::Number(serde_json::Number::from(val)));
-                                } else {
-                                    let value = val as f64 * factor;
-                                    result_js.insert(field_name.to_string(), serde_json::Value::Number(serde_json::Number::from_f64(value).unwrap()));
-                                }
-                            },                 
-                            _ => {
-                                println!("                Unsupported data type {}", data_type);
-                            }
-                        }
-                    }
-                    // print result_js
-                    println!("                Result: {}", serde_json::to_string(&serde_json::Value::Object(result_js)).unwrap());
-                }
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args::default();
+    let mut it = std::env::args().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--replay" => args.replay = Some(it.next().ok_or("--replay needs a file name")?),
+            "--check" => args.check = Some(it.next().ok_or("--check needs a file name")?),
+            "--list" => args.list = true,
+            "--selftest" => args.selftest = true,
+            "--config" => args.config = Some(existing_file("--config", it.next())?),
+            "--defs" => args.defs.push(existing_file("--defs", it.next())?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
             }
+            _ => return Err(format!("Unknown argument {}", arg)),
         }
     }
+    Ok(args)
 }
 
 fn main() {
-    let mut ebus_ip = "";
-    let mut ebus_port = 0;
-    let mut mqtt_ip: &str = "";
-    let mut mqtt_port: i32 = 0; 
-    let mut mqtt_user: &str = "";
-    let mut mqtt_pass: &str = "";
-    let mut mqtt_topic: &str = "";
+    logger::init();
 
-    // load config.json file 
-    let cfg : serde_json::Value = serde_json::from_reader(File::open("./config.json").expect("Failed to open config.json")).unwrap();
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            error!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
 
-    if cfg.as_object().unwrap().contains_key("ebus") {
-        ebus_ip = cfg["ebus"]["host"].as_str().unwrap();
-        ebus_port = cfg["ebus"]["port"].as_u64().unwrap() as i32;
-    } else {
-        ebus_ip = "192.168.2.45";
-        ebus_port = 9999;
+    // neither config.json nor hardware needed
+    if args.selftest {
+        // frame log lines would bury the report
+        logger::set_level(log::LevelFilter::Warn);
+        std::process::exit(if selftest::run() { 0 } else { 1 });
     }
 
-    if cfg.as_object().unwrap().contains_key("mqtt") {
-        mqtt_ip = cfg["mqtt"]["host"].as_str().unwrap();
-        mqtt_port = cfg["mqtt"]["port"].as_i64().unwrap() as i32;
-        mqtt_user = cfg["mqtt"]["user"].as_str().unwrap();
-        mqtt_pass = cfg["mqtt"]["pass"].as_str().unwrap();
-        mqtt_topic = cfg["mqtt"]["topic"].as_str().unwrap();
-    } else {
-        logI("No MQTT configuration found in config.json");
+    // lint definition file and exit, config.json is not needed
+    if let Some(filename) = &args.check {
+        std::process::exit(if check_definitions(filename) { 0 } else { 1 });
     }
 
-    let filename = "./ariston.json";
+    // load config.json file 
+    let cfg = match Config::load(args.config.as_deref().unwrap_or(DEFAULT_CONFIG)) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    logger::set_level(cfg.log_level.filter());
+    address::set_names(cfg.address_names());
 
-    // Open the file in read-only mode with buffer.
-    let file = File::open(filename).expect("Failed to open file");
-    let reader = BufReader::new(file);
+    let mqtt = match &cfg.mqtt {
+        // listing doesn't publish anything
+        Some(_) if args.list => None,
+        Some(m) => match MqttPublisher::connect(m) {
+            Ok(mqtt) => Some(mqtt),
+            Err(e) => {
+                error!("Failed to set up MQTT TLS: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            info!("No MQTT configuration found in config.json");
+            None
+        }
+    };
+
+    // definition files to load, --defs or ebus.definitions in config.json
+    // (relative to directory of --config file when given)
+    let config_dir = args.config.as_ref().and_then(|c| std::path::Path::new(c).parent());
+    let filenames: Vec<String> = if args.defs.is_empty() {
+        cfg.ebus.definitions.iter()
+            .filter(|f| !f.is_empty())
+            .map(|f| config_dir.map_or(f.clone(), |dir| dir.join(f).to_string_lossy().into_owned()))
+            .collect()
+    } else {
+        args.defs.clone()
+    };
 
-    // Read the JSON contents of the file as untyped
-    let u : serde_json::Value = serde_json::from_reader(reader).unwrap();
-    let mut mapper: Mapper = Mapper::new(u.clone());
-    println!("{:?}", u);
-    println!("Loaded comm definitions from file {}", filename);
-    println!("     Appliance: {}", u["appliance"].as_str().unwrap());
-    println!("     Bus: {}", u["bus"].as_str().unwrap());
+    let mut defs = Vec::new();
+    let mut invalid = false;
+    for filename in &filenames {
+        let Some(u) = load_definitions(filename) else {
+            invalid = true;
+            continue;
+        };
+        // stdout is kept for telegrams only in jsonl mode
+        if cfg.output == OutputMode::Text && !args.list {
+            println!("{:?}", u);
+            println!("Loaded comm definitions from file {}", filename);
+            println!("     Appliance: {}", u["appliance"].as_str().unwrap());
+            println!("     Bus: {}", u["bus"].as_str().unwrap());
+        }
+        defs.push(u);
+    }
+    if invalid {
+        std::process::exit(1);
+    }
+    let options = MapperOptions {
+        output: cfg.output,
+        include_timing: cfg.include_timing,
+        include_acked: cfg.include_acked,
+        include_raw: cfg.include_raw,
+        nested_output: cfg.nested_output,
+        log_unmatched: cfg.log_unmatched,
+        unmatched_topic: cfg.unmatched_topic.clone(),
+        retain: cfg.mqtt.as_ref().is_some_and(|m| m.retain),
+        include_units: cfg.include_units,
+        publish_on_change: cfg.publish_on_change,
+        max_staleness: cfg.publish_max_age.map(Duration::from_secs),
+        decimals: cfg.decimals,
+        enabled_circuits: cfg.enabled_circuits.clone(),
+        disabled_circuits: cfg.disabled_circuits.clone(),
+        publish_last_seen: cfg.publish_last_seen,
+        // replayed captures must not overwrite state of the live bridge
+        state_file: cfg.state_file.clone().filter(|_| args.replay.is_none()),
+        // values held back in replay would be lost when it ends
+        min_publish_interval: cfg.min_publish_interval_ms.map(Duration::from_millis).filter(|_| args.replay.is_none()),
+        only_src: Config::address_filter(&cfg.only_src),
+        only_dest: Config::address_filter(&cfg.only_dest),
+    };
+    // kept for disconnecting on shutdown
+    let mqtt_handle = mqtt.clone();
+    let mut mapper: Mapper = Mapper::new(defs, mqtt, options);
+    if args.list {
+        mapper.print_definitions();
+        return;
+    }
+    if cfg.ha_discovery {
+        mapper.publish_discovery();
+    }
+    mapper.restore_state();
+
+    // offline mode - decode capture file and exit
+    if let Some(filename) = &args.replay {
+        let capture = match replay::read_capture(filename) {
+            Ok(capture) => capture,
+            Err(e) => {
+                error!("Failed to read capture {}: {}", filename, e);
+                std::process::exit(1);
+            }
+        };
+        info!("Replaying {} bytes from {}", capture.bytes.len(), filename);
+        // replayed frames must not end up in the capture log of the live bridge
+        let mut parser = cfg.parser_options().build(move |a,b| { mapper.received_telegram(a,b) });
+        if let Some(protocol) = capture.protocol {
+            parser.set_protocol(protocol);
+        }
+        replay::replay(&capture.bytes, &mut parser);
+        if let Some(mqtt) = mqtt_handle {
+            mqtt.disconnect();
+        }
+        return;
+    }
     
-    // Create a TCP stream
-    let mut stream = TcpStream::connect(format!("{}:{}", ebus_ip, ebus_port)).expect("Failed to connect");
+    // Open ebus interface, either TCP stream or serial port (ebus.type)
+    let source = cfg.ebus.source();
+    let stream = match source.open() {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Failed to connect to {}: {}", source, e);
+            if let Some(mqtt) = &mqtt_handle {
+                mqtt.disconnect();
+            }
+            std::process::exit(1);
+        }
+    };
+
+    // Prometheus endpoint, fed with parser stats below and field values by mapper
+    let metrics = cfg.metrics.as_ref().and_then(|m| {
+        let metrics = Metrics::default();
+        match metrics.serve(&format!("{}:{}", m.bind, m.port)) {
+            Ok(()) => Some(metrics),
+            Err(e) => {
+                error!("Failed to serve metrics on port {}: {}", m.port, e);
+                None
+            }
+        }
+    });
+    if let Some(metrics) = &metrics {
+        mapper.set_metrics(metrics.clone());
+    }
+
+    // bus writes of MQTT commands, parser passes adapter responses on to sender
+    let mut bus_events = None;
+    let sender = match (&cfg.ebus.write_address, &mqtt_handle) {
+        (Some(addr), Some(mqtt)) => match stream.writer() {
+            Ok(writer) => {
+                // address is checked by Config::load
+                let commands = Commands::new(mapper.command_messages(), address::parse_address(addr).unwrap());
+                info!("Accepting commands for {} writable message(s)", commands.topics().len());
+                let (events, events_rx) = mpsc::channel();
+                bus_events = Some(events);
+                let sender = Arc::new(Mutex::new(EbusSender::new(writer, events_rx)));
+                let incoming = mqtt.subscribe(commands.topics());
+                let (sender_clone, mqtt) = (sender.clone(), mqtt.clone());
+                thread::spawn(move || commands.run(incoming, sender_clone, &mqtt));
+                Some(sender)
+            }
+            Err(e) => {
+                error!("Can't write to {}, commands disabled: {}", source, e);
+                None
+            }
+        },
+        (Some(_), None) => {
+            warn!("ebus.write_address is set, but commands need mqtt");
+            None
+        }
+        _ => None
+    };
 
     // Create a flag to indicate when to stop receiving data
     let running = Arc::new(AtomicBool::new(true));
     let running_clone = running.clone();
 
     // Spawn a thread to receive and print data
+    let mqtt_stats = mqtt_handle.clone();
     let handle = thread::spawn(move || {
-        let mut buffer = [0; 1024];
-        let mut parser = EbusParser::new(move |a,b| { mapper.received_telegram(a,b) });
-        while running_clone.load(Ordering::Relaxed) {
-            match stream.read(&mut buffer) {
-                Ok(n) if n > 0 => {
-                    parser.feed(&buffer[0..n], n);
-                    // for i in 0..n {
-                    //     print!("{:02X} ", buffer[i]);
-                    // }
-                    // println!();
-                }
-                Ok(_) => break,
-                Err(_) => break,
-            }
+        // mapper is shared with parser callback, state is saved on exit
+        let mapper = Arc::new(Mutex::new(mapper));
+        let mapper_clone = mapper.clone();
+        let mut builder = cfg.parser_builder();
+        if let Some(events) = bus_events {
+            builder = builder.event_sink(events);
         }
+        let mut parser = builder.build(move |a,b| { mapper_clone.lock().unwrap().received_telegram(a,b) });
+        let mut stats_published = Instant::now();
+        let (mapper_tick, mqtt_tick) = (mapper.clone(), mqtt_stats.clone());
+        let running_reconnect = running_clone.clone();
+        let watchdog_exit = cfg.ebus.watchdog_exit;
+        Receiver::new(cfg.ebus.read_buffer_size)
+            .watchdog(cfg.ebus.watchdog_timeout.map(Duration::from_secs))
+            .on_tick(move |parser| {
+                if let Some(mqtt) = &mqtt_tick {
+                    if stats_published.elapsed() >= STATS_INTERVAL {
+                        let topic = format!("{}/$stats", mqtt.base_topic());
+                        mqtt.publish(&topic, serde_json::to_string(parser.stats()).unwrap());
+                        stats_published = Instant::now();
+                    }
+                }
+                if let Some(metrics) = &metrics {
+                    metrics.set_stats(parser.stats());
+                }
+                // values held back while the bus is quiet
+                mapper_tick.lock().unwrap().publish_throttled();
+            })
+            .on_interrupted(move |interruption| {
+                match interruption {
+                    Interruption::Lost => warn!("Connection to {} lost", source),
+                    Interruption::Stalled(idle) => {
+                        error!("No frame from {} for {}s", source, idle.as_secs());
+                        if watchdog_exit {
+                            // let supervisor restart us
+                            if let Some(mqtt) = &mqtt_stats {
+                                mqtt.disconnect();
+                            }
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                let stream = reconnect(&source, &running_reconnect, mqtt_stats.as_ref())?;
+                if let Some(sender) = &sender {
+                    match stream.writer() {
+                        Ok(writer) => sender.lock().unwrap().set_writer(writer),
+                        Err(e) => warn!("Can't write to {}: {}", source, e),
+                    }
+                }
+                Some(stream)
+            })
+            .run(stream, &mut parser, &running_clone);
+        mapper.lock().unwrap().save_state();
     });
 
-    // Wait for a keypress to stop receiving data
-    let _ = std::io::stdin().read(&mut [0u8]).unwrap();
-
-    // Set the flag to stop receiving data
-    running.store(false, Ordering::Relaxed);
+    // Ctrl-C / SIGTERM sets the flag to stop receiving data
+    let running_signal = running.clone();
+    ctrlc::set_handler(move || {
+        info!("Shutting down...");
+        running_signal.store(false, Ordering::Relaxed);
+    }).expect("Failed to set signal handler");
 
     // Wait for the receiving thread to finish
     let _ = handle.join();
+
+    // flush pending publishes and disconnect
+    if let Some(mqtt) = mqtt_handle {
+        mqtt.disconnect();
+    }
 }
\ No newline at end of file
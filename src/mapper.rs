@@ -0,0 +1,1834 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::ebus::address;
+use crate::ebus::parser::{EbusRequest, EbusResponse, TelegramKind};
+use crate::metrics::Metrics;
+use crate::mqtt::{self, MqttPublisher};
+
+
+/*
+    fn match_field() is matching value_hex with field_def
+
+    request_match keys: src, dst, data and either pbsb or pb + sb, optional len
+    (request data length, number or "*") tells apart messages of same pbsb
+    values are matched as uppercase hex, pbsb always as 4 digits in telegram
+    byte order (PB first, so "^B5" matches any B5xx), patterns are case-insensitive
+    optional message "kind" (broadcast / master_master / master_slave) limits
+    message to telegrams of that kind
+    broadcasts (dst FE) never get a response, their fields come from request_map
+    only and response_map is ignored
+
+    Field matching syntax:
+    *       - any value matches
+    ^<hex>  - value starts with <hex>
+    <hex>   - value matches exactly (same length), '*' inside <hex> matches any single char
+    [lo-hi] - first byte of value is within lo..=hi (eg. [03-0A])
+    Array of patterns matches when any of them does (eg. ["B509", "B510"]).
+ */
+
+pub fn match_field(value_hex:&str, field_def:&serde_json::Value) -> bool {
+    match field_def.as_array() {
+        Some(patterns) => patterns.iter().any(|p| p.as_str().is_some_and(|p| match_pattern(value_hex, p))),
+        // non-string patterns never match
+        None => field_def.as_str().is_some_and(|p| match_pattern(value_hex, p))
+    }
+}
+
+fn match_pattern(value_hex:&str, field_pattern:&str) -> bool {
+    // check for all-match
+    if field_pattern == "*" {
+        return true;
+    }
+    // check for starts-with
+    if let Some(prefix) = field_pattern.strip_prefix('^') {
+        return value_hex.get(..prefix.len()).is_some_and(|v| v.eq_ignore_ascii_case(prefix));
+    }
+    // check for range of first byte
+    if let Some(range) = field_pattern.strip_prefix('[').and_then(|r| r.strip_suffix(']')) {
+        return match (parse_range(range), value_hex.get(..2).and_then(|b| u8::from_str_radix(b, 16).ok())) {
+            (Some((lo, hi)), Some(b)) => lo <= b && b <= hi,
+            _ => false
+        };
+    }
+    // check for exact match, '*' matches any single position
+    let pattern = field_pattern.as_bytes();
+    let value = value_hex.as_bytes();
+    if pattern.len() != value.len() {
+        return false;
+    }
+    pattern.iter().zip(value).all(|(p, v)| *p == b'*' || p.eq_ignore_ascii_case(v))
+}
+
+// parses "lo-hi" hex byte range of match pattern
+fn parse_range(range: &str) -> Option<(u8, u8)> {
+    let (lo, hi) = range.split_once('-')?;
+    Some((u8::from_str_radix(lo, 16).ok()?, u8::from_str_radix(hi, 16).ok()?))
+}
+
+// converts raw integer field value into json number, applying factor and then offset
+// (offset is therefore in output units), factor == 1.0 with no offset keeps the value
+// integer, otherwise it becomes float
+// (from_f64 only fails for NaN/inf, which is emitted as null)
+fn scaled_value(val: i64, factor: Scale, bias: f64) -> serde_json::Value {
+    if factor.is_one() && bias == 0.0 {
+        serde_json::Value::Number(serde_json::Number::from(val))
+    } else {
+        match serde_json::Number::from_f64(factor.apply(val as f64) + bias) {
+            Some(n) => serde_json::Value::Number(n),
+            None => serde_json::Value::Null
+        }
+    }
+}
+
+// field "factor" multiplying raw value, or "divisor" dividing it (value / 16),
+// divided directly as 0.1 factors aren't exact in binary
+#[derive(Debug, Clone, Copy)]
+enum Scale {
+    Factor(f64),
+    Divisor(f64),
+}
+
+impl Scale {
+    fn of(field: &serde_json::Value) -> Scale {
+        match field.get("divisor").and_then(|d| d.as_f64()) {
+            Some(divisor) => Scale::Divisor(divisor),
+            None => Scale::Factor(field.get("factor").and_then(|f| f.as_f64()).unwrap_or(1.0))
+        }
+    }
+
+    fn apply(self, val: f64) -> f64 {
+        match self {
+            Scale::Factor(f) => val * f,
+            Scale::Divisor(d) => val / d,
+        }
+    }
+
+    fn is_one(self) -> bool {
+        matches!(self, Scale::Factor(1.0) | Scale::Divisor(1.0))
+    }
+}
+
+impl std::fmt::Display for Scale {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Scale::Factor(factor) => write!(f, "{}", factor),
+            Scale::Divisor(divisor) => write!(f, "1/{}", divisor),
+        }
+    }
+}
+
+// converts already decoded (non-integer) field value into json number, applying factor and offset
+fn float_value(val: Option<f64>, factor: Scale, bias: f64) -> serde_json::Value {
+    match val.and_then(|v| serde_json::Number::from_f64(factor.apply(v) + bias)) {
+        Some(n) => serde_json::Value::Number(n),
+        None => serde_json::Value::Null
+    }
+}
+
+// number of data bytes consumed by data_type, None for unknown types
+fn field_width(data_type: &str) -> Option<usize> {
+    match data_type {
+        "u8" | "s8" | "DATA1b" | "DATA1c" | "bcd" | "bit" | "bits" | "percent0" | "percent_half" => Some(1),
+        "u16le" | "u16he" | "s16le" | "s16he" | "DATA2b" | "DATA2c" | "bcd16" => Some(2),
+        "u24le" | "u24he" | "bti" | "bda3" => Some(3),
+        "u32le" | "u32he" | "f32le" | "f32be" | "bda" => Some(4),
+        _ => None
+    }
+}
+
+// field names repeat across messages of a circuit (eg. "temp"), messages
+// without comment are told apart by pbsb of their request_match
+fn discovery_unique_id(circuit_name: &str, msg: &serde_json::Value, field_name: &str) -> String {
+    let message_name = match msg["comment"].as_str() {
+        Some(comment) => comment.to_string(),
+        None => {
+            let request_match = &msg["request_match"];
+            match request_match.get("pbsb") {
+                Some(pbsb) => pattern_name(pbsb),
+                None => format!("{}{}", pattern_name(&request_match["pb"]), pattern_name(&request_match["sb"]))
+            }
+        }
+    };
+    format!("ebus_{}_{}_{}", mqtt::topic_segment(circuit_name), mqtt::topic_segment(&message_name), mqtt::topic_segment(field_name))
+}
+
+// pattern or patterns of request_match joined by '_' (eg. "B509_B510")
+fn pattern_name(patterns: &serde_json::Value) -> String {
+    match patterns.as_array() {
+        Some(patterns) => patterns.iter().filter_map(|p| p.as_str()).collect::<Vec<_>>().join("_"),
+        None => patterns.as_str().unwrap_or("").to_string()
+    }
+}
+
+// field "unit", percent types default to "%"
+fn field_unit(field: &serde_json::Value) -> &str {
+    match field.get("unit").and_then(|u| u.as_str()) {
+        Some(unit) => unit,
+        None if matches!(field["data_type"].as_str(), Some("percent0" | "percent_half")) => "%",
+        None => ""
+    }
+}
+
+fn is_known_type(data_type: &str) -> bool {
+    field_width(data_type).is_some() || matches!(data_type, "hex" | "ascii" | "uint" | "int")
+}
+
+// pattern string or non-empty array of them
+fn is_pattern(value: &serde_json::Value) -> bool {
+    match value.as_array() {
+        Some(patterns) => !patterns.is_empty() && patterns.iter().all(|p| p.is_string()),
+        None => value.is_string()
+    }
+}
+
+// checks structure of loaded definition file, returns all problems found
+// as "<path>: <problem>" (eg. "circuits[0].messages[2].response_map[1]: missing field_offset or not an unsigned integer")
+// optional keys (factor, divisor, ..) are only checked when given
+pub fn validate_definitions(def: &serde_json::Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    for key in ["appliance", "bus"] {
+        if !def[key].is_string() {
+            errors.push(format!("{}: missing or not a string", key));
+        }
+    }
+    let circuits = match def["circuits"].as_array() {
+        Some(c) => c,
+        None => {
+            errors.push("circuits: missing or not an array".to_string());
+            return errors;
+        }
+    };
+    for (ci, circuit) in circuits.iter().enumerate() {
+        let path = format!("circuits[{}]", ci);
+        if !circuit["name"].is_string() {
+            errors.push(format!("{}: missing name", path));
+        }
+        let messages = match circuit["messages"].as_array() {
+            Some(m) => m,
+            None => {
+                errors.push(format!("{}: messages missing or not an array", path));
+                continue;
+            }
+        };
+        for (mi, msg) in messages.iter().enumerate() {
+            let path = format!("{}.messages[{}]", path, mi);
+            let request_match = &msg["request_match"];
+            if !request_match.is_object() {
+                errors.push(format!("{}: request_match missing or not an object", path));
+            } else {
+                for key in ["src", "dst", "data"] {
+                    if !is_pattern(&request_match[key]) {
+                        errors.push(format!("{}.request_match: {} missing or not a pattern", path, key));
+                    }
+                }
+                for key in ["pbsb", "pb", "sb"] {
+                    if request_match.get(key).is_some_and(|v| !is_pattern(v)) {
+                        errors.push(format!("{}.request_match: {} is not a pattern", path, key));
+                    }
+                }
+                if request_match.get("len").is_some_and(|l| l != "*" && l.as_u64().is_none_or(|l| l > u8::MAX as u64)) {
+                    errors.push(format!("{}.request_match: len is not a length (0..255) or \"*\"", path));
+                }
+            }
+            if let Some(kind) = msg.get("kind") {
+                if !matches!(kind.as_str(), Some("broadcast" | "master_master" | "master_slave")) {
+                    errors.push(format!("{}: kind is not one of broadcast, master_master, master_slave", path));
+                }
+            }
+            // "writable": true subscribes <message topic>/set (see encode_request)
+            match msg.get("writable") {
+                None | Some(serde_json::Value::Bool(false)) => {}
+                Some(serde_json::Value::Bool(true)) => {
+                    let single_dst = request_match["dst"].as_str().is_some_and(|d| d.len() == 2 && u8::from_str_radix(d, 16).is_ok());
+                    if !single_dst || exact_pbsb(request_match).is_none_or(|p| p.len() != 1) {
+                        errors.push(format!("{}: writable message needs single dst address and pbsb", path));
+                    }
+                    if msg.get("request_map").is_none() {
+                        errors.push(format!("{}: writable message needs request_map", path));
+                    }
+                }
+                Some(_) => errors.push(format!("{}: writable is not a boolean", path)),
+            }
+            // broadcast can't be decoded by response_map alone
+            let broadcast = msg["kind"] == "broadcast" || msg["request_match"]["dst"].as_str().is_some_and(|d| d.eq_ignore_ascii_case("FE"));
+            if broadcast && msg.get("response_map").is_some() && msg.get("request_map").is_none() {
+                errors.push(format!("{}: broadcast has no response, fields must be in request_map", path));
+            }
+            // request_map and response_map fields end up in the same result
+            let mut field_names = Vec::new();
+            for map in ["request_map", "response_map"] {
+                let fields = match msg.get(map) {
+                    None => continue,
+                    Some(f) => match f.as_array() {
+                        Some(f) => f,
+                        None => {
+                            errors.push(format!("{}: {} is not an array", path, map));
+                            continue;
+                        }
+                    }
+                };
+                for (fi, field) in fields.iter().enumerate() {
+                    let path = format!("{}.{}[{}]", path, map, fi);
+                    match field["field_name"].as_str() {
+                        Some(name) if field_names.contains(&name) => errors.push(format!("{}: field_name {} used twice in message", path, name)),
+                        Some(name) => field_names.push(name),
+                        None => errors.push(format!("{}: missing field_name", path)),
+                    }
+                    if !field["field_offset"].is_u64() {
+                        errors.push(format!("{}: missing field_offset or not an unsigned integer", path));
+                    }
+                    if field.get("factor").is_some_and(|f| !f.is_number()) {
+                        errors.push(format!("{}: factor is not a number", path));
+                    }
+                    if field.get("divisor").is_some_and(|d| d.as_f64().is_none_or(|d| d == 0.0)) {
+                        errors.push(format!("{}: divisor is not a non-zero number", path));
+                    }
+                    if field.get("factor").is_some() && field.get("divisor").is_some() {
+                        errors.push(format!("{}: factor and divisor are exclusive", path));
+                    }
+                    for key in ["repeat", "stride"] {
+                        if field.get(key).is_some_and(|v| v.as_u64().is_none_or(|v| v == 0)) {
+                            errors.push(format!("{}: {} is not a positive integer", path, key));
+                        }
+                    }
+                    if field.get("repeat").is_some() && field.get("stride").is_none() && field["data_type"].as_str().and_then(field_width).is_none() {
+                        errors.push(format!("{}: stride required for repeated {}", path, field["data_type"].as_str().unwrap_or("field")));
+                    }
+                    for key in ["field_length", "bit_offset", "bit_width", "decimals"] {
+                        if field.get(key).is_some_and(|v| !v.is_u64()) {
+                            errors.push(format!("{}: {} is not an unsigned integer", path, key));
+                        }
+                    }
+                    if matches!(field["data_type"].as_str(), Some("uint" | "int")) {
+                        if field["bytes"].as_u64().is_none_or(|b| !(1..=4).contains(&b)) {
+                            errors.push(format!("{}: bytes must be 1 to 4", path));
+                        }
+                        if field.get("endian").is_some_and(|e| !matches!(e.as_str(), Some("le" | "be"))) {
+                            errors.push(format!("{}: endian must be le or be", path));
+                        }
+                    }
+                    if field.get("qos").is_some_and(|q| q.as_u64().is_none_or(|q| q > 2)) {
+                        errors.push(format!("{}: qos must be 0, 1 or 2", path));
+                    }
+                    for key in ["min", "max"] {
+                        if field.get(key).is_some_and(|v| !v.is_number()) {
+                            errors.push(format!("{}: {} is not a number", path, key));
+                        }
+                    }
+                    if field.get("include_raw").is_some_and(|r| !r.is_boolean()) {
+                        errors.push(format!("{}: include_raw is not a boolean", path));
+                    }
+                    if field.get("unit").is_some_and(|u| !u.is_string()) {
+                        errors.push(format!("{}: unit is not a string", path));
+                    }
+                    if let Some(values) = field.get("values") {
+                        match values.as_object() {
+                            Some(map) => for (key, name) in map {
+                                if key.parse::<i64>().is_err() || !name.is_string() {
+                                    errors.push(format!("{}: values entry \"{}\" must map an integer to a string", path, key));
+                                }
+                            },
+                            None => errors.push(format!("{}: values is not an object", path)),
+                        }
+                    }
+                    match field["data_type"].as_str() {
+                        Some(t) if !is_known_type(t) => errors.push(format!("{}: unknown data_type {}", path, t)),
+                        Some(_) => {}
+                        None => errors.push(format!("{}: missing data_type", path)),
+                    }
+                }
+            }
+        }
+    }
+    errors
+}
+
+// rounds float value to given decimal places, integers and nulls are kept
+// (eg. 44.99999 with 1 decimal -> 45.0)
+fn round_value(value: &serde_json::Value, decimals: u32) -> Option<serde_json::Value> {
+    if !value.is_f64() {
+        return None;
+    }
+    let scale = 10f64.powi(decimals.min(15) as i32);
+    let rounded = (value.as_f64()? * scale).round() / scale;
+    serde_json::Number::from_f64(rounded).map(serde_json::Value::Number)
+}
+
+// name of whole number value from field's "values" map
+fn value_name(field: &serde_json::Value, value: &serde_json::Value) -> Option<serde_json::Value> {
+    let val = value.as_f64()?;
+    if val.fract() != 0.0 {
+        return None;
+    }
+    field.get("values")?.get((val as i64).to_string()).cloned()
+}
+
+// decodes packed BCD byte (eg. 0x42 -> 42), None if any nibble > 9
+fn decode_bcd(b: u8) -> Option<u8> {
+    let (hi, lo) = (b >> 4, b & 0x0F);
+    if hi > 9 || lo > 9 {
+        None
+    } else {
+        Some(hi * 10 + lo)
+    }
+}
+
+
+// stdout output of decoded telegrams, independent of MQTT publishing
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    // human readable dump of telegrams and fields
+    #[default]
+    Text,
+    // one compact json object per telegram
+    Jsonl,
+    None,
+}
+
+// formats BTI time as "hh:mm:ss" and BDA date as "yyyy-mm-dd",
+// None for invalid BCD or out of range values
+fn decode_bcd_datetime(data_type: &str, raw: &[u8]) -> Option<String> {
+    let v: Vec<u8> = raw.iter().map(|b| decode_bcd(*b)).collect::<Option<_>>()?;
+    if data_type == "bti" {
+        let (sec, min, hour) = (v[0], v[1], v[2]);
+        if hour > 23 || min > 59 || sec > 59 {
+            return None;
+        }
+        Some(format!("{:02}:{:02}:{:02}", hour, min, sec))
+    } else {
+        // 4 byte BDA has weekday before year
+        let (day, month, year) = (v[0], v[1], v[v.len() - 1]);
+        if !(1..=31).contains(&day) || !(1..=12).contains(&month) {
+            return None;
+        }
+        Some(format!("{:04}-{:02}-{:02}", 2000 + year as u16, month, day))
+    }
+}
+
+// output options of Mapper, defaults keep plain `field: value` output
+#[derive(Debug, Default, Clone)]
+pub struct MapperOptions {
+    pub output: OutputMode,
+    // add frame timestamp ("ts"), slave response latency and inter-frame gap to published values
+    pub include_timing: bool,
+    // publish values with MQTT retain flag (discovery config is always retained)
+    pub retain: bool,
+    // nest printed result under circuit and message names, publish each field
+    // on its own topic below them (NESTED_TOPIC) unless message sets "topic"
+    pub nested_output: bool,
+    // report telegrams not matching any message, logged and / or published
+    // to unmatched_topic ({base} placeholder allowed)
+    pub log_unmatched: bool,
+    pub unmatched_topic: Option<String>,
+    // publish fields as { "value": ..., "unit": ... } objects
+    pub include_units: bool,
+    // publish field only when its value differs from the last published one
+    pub publish_on_change: bool,
+    // with publish_on_change, republish unchanged value after this long
+    pub max_staleness: Option<Duration>,
+    // decimal places float values are rounded to, unless field sets "decimals"
+    pub decimals: Option<u32>,
+    // circuits by name: when enabled_circuits is given only those are decoded,
+    // disabled_circuits are never
+    pub enabled_circuits: Option<Vec<String>>,
+    pub disabled_circuits: Vec<String>,
+    // publish ISO-8601 time of every match to <message topic>/last_seen
+    pub publish_last_seen: bool,
+    // last decoded values, saved every STATE_SAVE_INTERVAL and republished
+    // retained at startup (Mapper::restore_state)
+    pub state_file: Option<String>,
+    // at most one publish per message topic within interval, updates in between
+    // are merged and the newest values published once it has elapsed
+    pub min_publish_interval: Option<Duration>,
+    // add "acked" (request ACKed by destination) to values of non-broadcasts
+    pub include_acked: bool,
+    // telegrams from / to other addresses are ignored, all pass when None
+    pub only_src: Option<Vec<u8>>,
+    pub only_dest: Option<Vec<u8>>,
+    // add <field>_raw with the integer before factor / offset next to numeric
+    // fields, unless field sets "include_raw"
+    pub include_raw: bool,
+}
+
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// min_publish_interval bookkeeping of one message topic
+struct Throttle {
+    last: Option<Instant>,
+    // message index and fields waiting for the interval to elapse
+    pending: Option<(usize, serde_json::Map<String, serde_json::Value>)>,
+}
+
+impl MapperOptions {
+    fn circuit_enabled(&self, circuit_name: &str) -> bool {
+        self.enabled_circuits.as_ref().is_none_or(|e| e.iter().any(|c| c == circuit_name)) &&
+        !self.disabled_circuits.iter().any(|c| c == circuit_name)
+    }
+
+    fn telegram_wanted(&self, req: &EbusRequest) -> bool {
+        self.only_src.as_ref().is_none_or(|a| a.contains(&req.src())) &&
+        self.only_dest.as_ref().is_none_or(|a| a.contains(&req.dest()))
+    }
+}
+
+pub struct Mapper {
+    defs : Vec<serde_json::Value>,
+    mqtt : Option<MqttPublisher>,
+    options : MapperOptions,
+    // last published value per "circuit/message/field", for publish_on_change
+    last_published : HashMap<String, (serde_json::Value, Instant)>,
+    index : MessageIndex,
+    // latest numeric field values for Prometheus, when enabled
+    metrics : Option<Metrics>,
+    // last decoded fields for state_file, { circuit: { message: { field: value } } }
+    state : serde_json::Map<String, serde_json::Value>,
+    state_dirty : bool,
+    state_saved : Instant,
+    // per message topic, for min_publish_interval
+    throttled : HashMap<String, Throttle>,
+}
+
+// lookup of messages by pbsb, so a telegram is checked only against messages
+// which can match it instead of scanning all definitions
+struct MessageIndex {
+    // (definition, circuit, message) positions, in definition order
+    messages : Vec<(usize, usize, usize)>,
+    // messages with exact pbsb patterns
+    by_pbsb : HashMap<u16, Vec<usize>>,
+    // messages with wildcard / prefix / range pbsb patterns, checked always
+    any_pbsb : Vec<usize>,
+}
+
+impl MessageIndex {
+    // messages of disabled circuits are left out
+    fn new(defs: &[serde_json::Value], options: &MapperOptions) -> MessageIndex {
+        let mut index = MessageIndex { messages: Vec::new(), by_pbsb: HashMap::new(), any_pbsb: Vec::new() };
+        for (d, def) in defs.iter().enumerate() {
+            for (c, circuit) in def["circuits"].as_array().unwrap().iter().enumerate() {
+                if !options.circuit_enabled(circuit["name"].as_str().unwrap()) {
+                    continue;
+                }
+                for (m, msg) in circuit["messages"].as_array().unwrap().iter().enumerate() {
+                    let i = index.messages.len();
+                    index.messages.push((d, c, m));
+                    match exact_pbsb(&msg["request_match"]) {
+                        Some(keys) => keys.into_iter().for_each(|k| index.by_pbsb.entry(k).or_default().push(i)),
+                        None => index.any_pbsb.push(i),
+                    }
+                }
+            }
+        }
+        index
+    }
+
+    // message positions to check for pbsb, in definition order
+    fn candidates(&self, pbsb: u16) -> Vec<usize> {
+        let mut candidates = self.any_pbsb.clone();
+        if let Some(exact) = self.by_pbsb.get(&pbsb) {
+            candidates.extend(exact);
+            candidates.sort_unstable();
+            candidates.dedup();
+        }
+        candidates
+    }
+}
+
+// exact hex pattern (or array of them) as values, None when any is not exact
+fn exact_values(pattern: &serde_json::Value, digits: usize) -> Option<Vec<u16>> {
+    let parse = |p: &serde_json::Value| {
+        let p = p.as_str()?;
+        if p.len() != digits {
+            return None;
+        }
+        u16::from_str_radix(p, 16).ok()
+    };
+    match pattern.as_array() {
+        Some(patterns) => patterns.iter().map(parse).collect(),
+        None => parse(pattern).map(|v| vec![v])
+    }
+}
+
+// pbsb values matched by request_match, given combined or as pb + sb,
+// None when message can match any pbsb
+fn exact_pbsb(request_match: &serde_json::Value) -> Option<Vec<u16>> {
+    if let Some(pbsb) = request_match.get("pbsb") {
+        return exact_values(pbsb, 4);
+    }
+    let pbs = exact_values(request_match.get("pb")?, 2)?;
+    let sbs = exact_values(request_match.get("sb")?, 2)?;
+    Some(pbs.iter().flat_map(|pb| sbs.iter().map(move |sb| pb << 8 | sb)).collect())
+}
+
+impl Mapper {
+    pub fn new(defs : Vec<serde_json::Value>, mqtt : Option<MqttPublisher>, options : MapperOptions) -> Mapper {
+        let index = MessageIndex::new(&defs, &options);
+        let names: Vec<&str> = defs.iter().flat_map(|d| d["circuits"].as_array().unwrap()).filter_map(|c| c["name"].as_str()).collect();
+        for name in options.enabled_circuits.iter().flatten().chain(&options.disabled_circuits) {
+            if !names.contains(&name.as_str()) {
+                warn!("Circuit {} of enabled_circuits / disabled_circuits is not defined", name);
+            }
+        }
+        Mapper { defs, mqtt, options, last_published: HashMap::new(), index, metrics: None,
+            state: serde_json::Map::new(), state_dirty: false, state_saved: Instant::now(),
+            throttled: HashMap::new() }
+    }
+
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = Some(metrics);
+    }
+
+    // prints every loaded circuit, message match pattern and its fields
+    pub fn print_definitions(&self) {
+        for circuit in self.defs.iter().flat_map(|d| d["circuits"].as_array().unwrap()) {
+            let circuit_name = circuit["name"].as_str().unwrap();
+            if self.options.circuit_enabled(circuit_name) {
+                println!("Circuit: {}", circuit_name);
+            } else {
+                println!("Circuit: {} (disabled)", circuit_name);
+            }
+            for msg in circuit["messages"].as_array().unwrap() {
+                println!("    Message: {}", msg["comment"].as_str().unwrap_or(""));
+                println!("        Match: {}", msg["request_match"]);
+                for map in ["request_map", "response_map"] {
+                    for field in msg[map].as_array().into_iter().flatten() {
+                        println!("        {}: {} @{} {} [{}]", if map == "request_map" { "Request" } else { "Response" },
+                            field["field_name"].as_str().unwrap(), field["field_offset"], field["data_type"].as_str().unwrap(),
+                            field_unit(field));
+                    }
+                }
+            }
+        }
+    }
+
+    // publishes retained Home Assistant discovery config for every defined field,
+    // state topic and value template follow the way values are published
+    pub fn publish_discovery(&self) {
+        let publisher = match &self.mqtt {
+            Some(p) => p,
+            None => return
+        };
+        for circuit in self.defs.iter().flat_map(|d| d["circuits"].as_array().unwrap()) {
+            let circuit_name = circuit["name"].as_str().unwrap();
+            if !self.options.circuit_enabled(circuit_name) {
+                continue;
+            }
+            for msg in circuit["messages"].as_array().unwrap() {
+                let topic = message_topic(publisher, circuit_name, msg, self.options.nested_output);
+                let fields = ["request_map", "response_map"].iter()
+                    .filter_map(|m| msg[*m].as_array())
+                    .flatten();
+                for field in fields {
+                    let field_name = field["field_name"].as_str().unwrap();
+                    let unique_id = discovery_unique_id(circuit_name, msg, field_name);
+                    let value_path = if self.options.include_units { ".value" } else { "" };
+                    let mut payload = serde_json::json!({
+                        "name": field_name,
+                        "unique_id": unique_id,
+                        "object_id": unique_id,
+                    });
+                    if topic.contains("{field}") {
+                        payload["state_topic"] = topic.replace("{field}", &mqtt::topic_segment(field_name)).into();
+                        if self.options.include_units {
+                            payload["value_template"] = "{{ value_json.value }}".into();
+                        }
+                    } else {
+                        payload["state_topic"] = topic.clone().into();
+                        payload["value_template"] = format!("{{{{ value_json['{}']{} }}}}", field_name, value_path).into();
+                    }
+                    if let Some(unit) = Some(field_unit(field)).filter(|u| !u.is_empty()) {
+                        payload["unit_of_measurement"] = unit.into();
+                    }
+                    let discovery_topic = format!("homeassistant/sensor/{}/config", unique_id);
+                    publisher.publish_retained(&discovery_topic, payload.to_string());
+                }
+            }
+        }
+    }
+
+    // loads values saved to state_file and republishes them retained, so rarely
+    // sent messages have values right after restart
+    pub fn restore_state(&mut self) {
+        let Some(filename) = &self.options.state_file else {
+            return;
+        };
+        let state = match fs::read_to_string(filename) {
+            Ok(text) => serde_json::from_str::<serde_json::Value>(&text).map_err(io::Error::from),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => Err(e),
+        };
+        let state = match state {
+            Ok(serde_json::Value::Object(state)) => state,
+            Ok(_) => return warn!("State file {} is not a json object, ignored", filename),
+            Err(e) => return warn!("Failed to read state file {}: {}", filename, e),
+        };
+        let mut restored = 0;
+        for i in 0..self.index.messages.len() {
+            let (circuit_name, msg) = self.message(i);
+            let message_name = msg["comment"].as_str().unwrap_or("");
+            let Some(fields) = state.get(circuit_name).and_then(|c| c.get(message_name)).and_then(|f| f.as_object()) else {
+                continue;
+            };
+            if let Some(publisher) = &self.mqtt {
+                publish_result(publisher, circuit_name, msg, fields.clone(), self.options.nested_output, true);
+            }
+            restored += 1;
+        }
+        info!("Restored {} message(s) from state file {}", restored, filename);
+        self.state = state;
+    }
+
+    // writes last decoded values to state_file (through temporary file, so
+    // a crash can't leave it half written)
+    pub fn save_state(&mut self) {
+        let Some(filename) = &self.options.state_file else {
+            return;
+        };
+        let tmp = format!("{}.tmp", filename);
+        let text = serde_json::to_string_pretty(&self.state).unwrap();
+        if let Err(e) = fs::write(&tmp, text).and_then(|_| fs::rename(&tmp, filename)) {
+            warn!("Failed to write state file {}: {}", filename, e);
+        }
+        self.state_dirty = false;
+        self.state_saved = Instant::now();
+    }
+
+    // writable messages of enabled circuits by command topic (<message topic>/set,
+    // without field placeholder of per-field topics), none without MQTT
+    pub fn command_messages(&self) -> HashMap<String, serde_json::Value> {
+        let Some(publisher) = &self.mqtt else {
+            return HashMap::new();
+        };
+        (0..self.index.messages.len())
+            .map(|i| self.message(i))
+            .filter(|(_, msg)| msg["writable"] == true)
+            .map(|(circuit_name, msg)| {
+                let topic = message_topic(publisher, circuit_name, msg, self.options.nested_output).replace("/{field}", "");
+                (format!("{}/set", topic), msg.clone())
+            })
+            .collect()
+    }
+
+    // publishes values held back by min_publish_interval once it has elapsed,
+    // called for every telegram and periodically by the receiver
+    pub fn publish_throttled(&mut self) {
+        let (Some(interval), Some(publisher)) = (self.options.min_publish_interval, &self.mqtt) else {
+            return;
+        };
+        for throttle in self.throttled.values_mut() {
+            if throttle.last.is_some_and(|t| t.elapsed() < interval) {
+                continue;
+            }
+            let Some((i, result_js)) = throttle.pending.take() else {
+                continue;
+            };
+            let (d, c, m) = self.index.messages[i];
+            let circuit = &self.defs[d]["circuits"][c];
+            publish_result(publisher, circuit["name"].as_str().unwrap(), &circuit["messages"][m], result_js,
+                self.options.nested_output, self.options.retain);
+            throttle.last = Some(Instant::now());
+        }
+    }
+
+    // raw telegram matching no message, for writing new definitions
+    fn report_unmatched(&self, req: &EbusRequest, resp: Option<&EbusResponse>) {
+        if !self.options.log_unmatched && self.options.unmatched_topic.is_none() {
+            return;
+        }
+        let mut raw = serde_json::json!({
+            "src": req.src_hex(),
+            "dest": req.dest_hex(),
+            "pbsb": req.pbsb_hex(),
+            "data": req.data_hex(),
+        });
+        if let Some(r) = resp {
+            raw["response"] = r.data_hex().into();
+        }
+        if self.options.log_unmatched {
+            info!("Unmatched telegram {}", raw);
+        }
+        if let (Some(publisher), Some(topic)) = (&self.mqtt, &self.options.unmatched_topic) {
+            publisher.publish(&topic.replace("{base}", publisher.base_topic()), raw.to_string());
+        }
+    }
+
+    // checks request against message's request_match (pbsb can be given
+    // combined or as separate pb / sb patterns) and optional kind
+    fn matches(msg: &serde_json::Value, req: &EbusRequest) -> bool {
+        let request_match = &msg["request_match"];
+        match_field(req.src_hex().as_str(), &request_match["src"]) &&
+        match_field(req.dest_hex().as_str(), &request_match["dst"]) &&
+        request_match.get("pbsb").is_none_or(|p| match_field(req.pbsb_hex().as_str(), p)) &&
+        request_match.get("pb").is_none_or(|p| match_field(req.pb_hex().as_str(), p)) &&
+        request_match.get("sb").is_none_or(|p| match_field(req.sb_hex().as_str(), p)) &&
+        match_field(req.data_hex().as_str(), &request_match["data"]) &&
+        request_match.get("len").is_none_or(|l| l == "*" || l.as_u64() == Some(req.len() as u64)) &&
+        msg["kind"].as_str().is_none_or(|k| k == req.kind().as_str())
+    }
+
+    // circuit name and message definition at index position
+    fn message(&self, i: usize) -> (&str, &serde_json::Value) {
+        let (d, c, m) = self.index.messages[i];
+        let circuit = &self.defs[d]["circuits"][c];
+        (circuit["name"].as_str().unwrap(), &circuit["messages"][m])
+    }
+
+    // decodes fields of matched message, request_map and response_map fields
+    // together, None when it has no field map for the data we've got
+    // (eg. response_map, but no response)
+    fn decode_message(&self, msg: &serde_json::Value, req: &EbusRequest, resp: Option<&EbusResponse>) -> Option<serde_json::Map<String, serde_json::Value>> {
+        // ok, let's initialize json object with parsed response data
+        let mut result_js = serde_json::Map::new();
+        let fields = field_maps(msg, req, resp);
+        if fields.is_empty() {
+            return None;
+        }
+        // zero-length data or data shorter than the smallest field_offset leaves
+        // nothing to decode, single fields beyond data are skipped by decode_field
+        if fields.iter().all(|(field, bytes)| field["field_offset"].as_u64().unwrap() >= bytes.len() as u64) {
+            debug!("Message {}: no field within data, skipped", msg["comment"].as_str().unwrap_or(&req.pbsb_hex()));
+            return None;
+        }
+        // parse data with field definitions 
+        for (field, bytes) in fields {
+            let field_name = field["field_name"].as_str().unwrap();
+            match field["repeat"].as_u64() {
+                Some(repeat) => self.decode_repeated(field, repeat, bytes, &mut result_js),
+                None => self.decode_field(field, bytes, &mut result_js)
+            }
+            // wrap decoded value together with its unit (empty when not given)
+            if self.options.include_units {
+                if let Some(value) = result_js.remove(field_name) {
+                    result_js.insert(field_name.to_string(), serde_json::json!({ "value": value, "unit": field_unit(field) }));
+                }
+            }
+        }
+        Some(result_js)
+    }
+
+    // "repeat" records of "stride" bytes (field width by default) starting at
+    // field_offset, decoded one by one into an array of as many as data holds
+    fn decode_repeated(&self, field: &serde_json::Value, repeat: u64, bytes: &[u8], result_js: &mut serde_json::Map<String, serde_json::Value>) {
+        let field_name = field["field_name"].as_str().unwrap();
+        let offset = field["field_offset"].as_u64().unwrap();
+        // checked by validate_definitions
+        let stride = field["stride"].as_u64()
+            .or_else(|| field_width(field["data_type"].as_str().unwrap()).map(|w| w as u64))
+            .unwrap_or(1);
+        let mut record = field.clone();
+        let mut values = Vec::new();
+        let mut raws = Vec::new();
+        for i in 0..repeat {
+            let record_offset = offset + i * stride;
+            if record_offset >= bytes.len() as u64 {
+                break;
+            }
+            record["field_offset"] = record_offset.into();
+            let mut decoded = serde_json::Map::new();
+            self.decode_field(&record, bytes, &mut decoded);
+            // position is kept for values out of range
+            values.push(decoded.remove(field_name).unwrap_or(serde_json::Value::Null));
+            raws.push(decoded.remove(&format!("{}_raw", field_name)).unwrap_or(serde_json::Value::Null));
+        }
+        if (values.len() as u64) < repeat {
+            warn!("Field {}: data holds {} of {} repeats", field_name, values.len(), repeat);
+        }
+        result_js.insert(field_name.to_string(), serde_json::Value::Array(values));
+        if raws.iter().any(|r| !r.is_null()) {
+            result_js.insert(format!("{}_raw", field_name), serde_json::Value::Array(raws));
+        }
+    }
+
+    // decodes single field from its bytes into result_js, nothing is inserted
+    // when data is too short or value is out of range
+    fn decode_field(&self, field: &serde_json::Value, bytes: &[u8], result_js: &mut serde_json::Map<String, serde_json::Value>) {
+        let field_name = field["field_name"].as_str().unwrap();
+        let offset = field["field_offset"].as_u64().unwrap();
+        let data_type = field["data_type"].as_str().unwrap();
+        // factor 1.0 when not given
+        let factor = Scale::of(field);
+        // optional "offset", added after factor
+        let bias = field["offset"].as_f64().unwrap_or(0.0);
+        // make sure all bytes of the field are present before indexing
+        // (hex / ascii take field_length bytes, rest of data by default,
+        // uint / int take "bytes")
+        let width = match data_type {
+            "hex" | "ascii" => Some(field["field_length"].as_u64().map(|l| l as usize).unwrap_or(bytes.len().saturating_sub(offset as usize))),
+            "uint" | "int" => Some(field["bytes"].as_u64().unwrap_or(1) as usize),
+            _ => field_width(data_type)
+        };
+        if let Some(width) = width {
+            // at least the first byte, even for empty hex / ascii
+            if offset as usize + width.max(1) > bytes.len() {
+                warn!("Field {}: needs {} bytes at offset {}, data has {}, skipped", field_name, width, offset, bytes.len());
+                return;
+            }
+        }
+        // undecoded integer of numeric types, for include_raw
+        let mut unscaled: Option<i64> = None;
+        match data_type {
+            "u8" => {
+                let val: u8 = bytes[offset as usize];
+                unscaled = Some(val as i64);
+                result_js.insert(field_name.to_string(), scaled_value(val as i64, factor, bias));
+            },
+            "u16le" => {
+                let val: u16 = (bytes[offset as usize] as u16) | ((bytes[offset as usize + 1] as u16) << 8);
+                unscaled = Some(val as i64);
+                result_js.insert(field_name.to_string(), scaled_value(val as i64, factor, bias));
+            },                  
+            "u16he" => {
+                let val: u16 = ((bytes[offset as usize] as u16) << 8) | (bytes[offset as usize + 1] as u16);
+                unscaled = Some(val as i64);
+                result_js.insert(field_name.to_string(), scaled_value(val as i64, factor, bias));
+            },                 
+            // counters wider than 16 bits
+            "u24le" | "u24he" | "u32le" | "u32he" => {
+                let o = offset as usize;
+                let raw = &bytes[o..o + width.unwrap()];
+                let val = if data_type.ends_with("le") {
+                    raw.iter().rev().fold(0u32, |acc, b| (acc << 8) | *b as u32)
+                } else {
+                    raw.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32)
+                };
+                unscaled = Some(val as i64);
+                result_js.insert(field_name.to_string(), scaled_value(val as i64, factor, bias));
+            },
+            "s8" => {
+                let val: i8 = bytes[offset as usize] as i8;
+                unscaled = Some(val as i64);
+                result_js.insert(field_name.to_string(), scaled_value(val as i64, factor, bias));
+            },
+            "s16le" => {
+                let val: i16 = i16::from_le_bytes([bytes[offset as usize], bytes[offset as usize + 1]]);
+                unscaled = Some(val as i64);
+                result_js.insert(field_name.to_string(), scaled_value(val as i64, factor, bias));
+            },
+            "s16he" => {
+                let val: i16 = i16::from_be_bytes([bytes[offset as usize], bytes[offset as usize + 1]]);
+                unscaled = Some(val as i64);
+                result_js.insert(field_name.to_string(), scaled_value(val as i64, factor, bias));
+            },
+            // eBUS standard types, multi-byte values are low byte first
+            // replacement values (0x80 / 0xFF / 0x8000) mean "no data" and emit null
+            "DATA1b" => {
+                let val: i8 = bytes[offset as usize] as i8;
+                unscaled = Some(val as i64);
+                let value = if val == i8::MIN { None } else { Some(val as f64) };
+                result_js.insert(field_name.to_string(), float_value(value, factor, bias));
+            },
+            "DATA1c" => {
+                let val: u8 = bytes[offset as usize];
+                unscaled = Some(val as i64);
+                let value = if val == 0xFF { None } else { Some(val as f64 / 2.0) };
+                result_js.insert(field_name.to_string(), float_value(value, factor, bias));
+            },
+            // byte scaled to percent, 0xFF (percent0) or 0xC8 (percent_half) is 100%,
+            // results over 100 are clamped
+            "percent0" | "percent_half" => {
+                let val: u8 = bytes[offset as usize];
+                unscaled = Some(val as i64);
+                let percent = if data_type == "percent0" { val as f64 * 100.0 / 255.0 } else { val as f64 / 2.0 };
+                if percent > 100.0 {
+                    warn!("Field {}: {:02X} is {}% as {}, clamped to 100", field_name, val, percent, data_type);
+                }
+                result_js.insert(field_name.to_string(), float_value(Some(percent.min(100.0)), factor, bias));
+            },
+            "DATA2b" => {
+                let val: i16 = i16::from_le_bytes([bytes[offset as usize], bytes[offset as usize + 1]]);
+                unscaled = Some(val as i64);
+                let value = if val == i16::MIN { None } else { Some(val as f64 / 256.0) };
+                result_js.insert(field_name.to_string(), float_value(value, factor, bias));
+            },
+            "DATA2c" => {
+                let val: i16 = i16::from_le_bytes([bytes[offset as usize], bytes[offset as usize + 1]]);
+                unscaled = Some(val as i64);
+                let value = if val == i16::MIN { None } else { Some(val as f64 / 16.0) };
+                result_js.insert(field_name.to_string(), float_value(value, factor, bias));
+            },
+            // packed BCD, bcd16 is low byte first (ebusd BCD:2 layout)
+            "bcd" => {
+                match decode_bcd(bytes[offset as usize]) {
+                    Some(val) => {
+                        unscaled = Some(val as i64);
+                        result_js.insert(field_name.to_string(), scaled_value(val as i64, factor, bias));
+                    },
+                    None => warn!("Field {}: invalid BCD byte {:02X}, skipped", field_name, bytes[offset as usize]),
+                }
+            },
+            "bcd16" => {
+                match (decode_bcd(bytes[offset as usize]), decode_bcd(bytes[offset as usize + 1])) {
+                    (Some(lo), Some(hi)) => {
+                        let val = hi as i64 * 100 + lo as i64;
+                        unscaled = Some(val);
+                        result_js.insert(field_name.to_string(), scaled_value(val, factor, bias));
+                    },
+                    _ => warn!("Field {}: invalid BCD bytes {:02X} {:02X}, skipped", field_name, bytes[offset as usize], bytes[offset as usize + 1]),
+                }
+            },
+            // IEEE-754 single precision floats
+            "f32le" | "f32be" => {
+                let o = offset as usize;
+                let raw = [bytes[o], bytes[o + 1], bytes[o + 2], bytes[o + 3]];
+                let bits = if data_type == "f32le" { u32::from_le_bytes(raw) } else { u32::from_be_bytes(raw) };
+                let val = f32::from_bits(bits);
+                result_js.insert(field_name.to_string(), float_value(Some(val as f64), factor, bias));
+            },
+            // flags packed in one byte, bit_offset 0 is the least significant bit
+            "bit" => {
+                let bit = field["bit_offset"].as_u64().unwrap_or(0);
+                if bit > 7 {
+                    warn!("Field {}: bit_offset {} out of range, skipped", field_name, bit);
+                    return;
+                }
+                let val = (bytes[offset as usize] >> bit) & 1 == 1;
+                result_js.insert(field_name.to_string(), serde_json::Value::Bool(val));
+            },
+            // unsigned integer of bit_width bits starting at bit_offset
+            "bits" => {
+                let bit = field["bit_offset"].as_u64().unwrap_or(0);
+                let width = field["bit_width"].as_u64().unwrap_or(1);
+                if width == 0 || bit + width > 8 {
+                    warn!("Field {}: bits {}..{} out of range, skipped", field_name, bit, bit + width);
+                    return;
+                }
+                let val = (bytes[offset as usize] >> bit) & (0xFF >> (8 - width));
+                unscaled = Some(val as i64);
+                result_js.insert(field_name.to_string(), scaled_value(val as i64, factor, bias));
+            },
+            // eBUS time (BCD ss mm hh) and date (BCD dd mm [weekday] yy),
+            // all bytes 0xFF is the "no data" replacement value and emits null
+            "bti" | "bda" | "bda3" => {
+                let o = offset as usize;
+                let raw = &bytes[o..o + width.unwrap()];
+                if raw.iter().all(|b| *b == 0xFF) {
+                    result_js.insert(field_name.to_string(), serde_json::Value::Null);
+                    return;
+                }
+                match decode_bcd_datetime(data_type, raw) {
+                    Some(text) => { result_js.insert(field_name.to_string(), serde_json::Value::String(text)); },
+                    None => warn!("Field {}: invalid {} bytes {:02X?}, skipped", field_name, data_type, raw),
+                }
+            },
+            // generic integers of "bytes" width and "endian" order (le by default)
+            "uint" | "int" => {
+                let o = offset as usize;
+                let raw = &bytes[o..o + width.unwrap()];
+                let val = if field["endian"].as_str() == Some("be") {
+                    raw.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+                } else {
+                    raw.iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+                };
+                // sign extend from top bit of the field
+                let shift = 64 - 8 * raw.len() as u32;
+                let val = if data_type == "int" { ((val << shift) as i64) >> shift } else { val as i64 };
+                unscaled = Some(val);
+                result_js.insert(field_name.to_string(), scaled_value(val, factor, bias));
+            },
+            // passthrough types, factor and unit are ignored
+            "hex" | "ascii" => {
+                let o = offset as usize;
+                let raw = &bytes[o..o + width.unwrap()];
+                let text = if data_type == "hex" {
+                    hex::encode_upper(raw)
+                } else {
+                    // Latin-1, trailing 0x00 padding dropped
+                    let end = raw.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+                    raw[..end].iter().map(|b| *b as char).collect()
+                };
+                result_js.insert(field_name.to_string(), serde_json::Value::String(text));
+            },
+            _ => warn!("Field {}: unsupported data type {}, skipped", field_name, data_type)
+        }
+        // kept even when value is dropped or named below, to find wrong factors
+        if let Some(raw) = unscaled.filter(|_| field["include_raw"].as_bool().unwrap_or(self.options.include_raw)) {
+            result_js.insert(format!("{}_raw", field_name), raw.into());
+        }
+        // field "decimals" overrides global one
+        let decimals = field["decimals"].as_u64().map(|d| d as u32).or(self.options.decimals);
+        if let Some(decimals) = decimals {
+            if let Some(rounded) = result_js.get(field_name).and_then(|v| round_value(v, decimals)) {
+                result_js.insert(field_name.to_string(), rounded);
+            }
+        }
+        // sanity range in output units (after factor / offset), values outside are dropped
+        if let Some(val) = result_js.get(field_name).and_then(|v| v.as_f64()) {
+            let min = field.get("min").and_then(|m| m.as_f64());
+            let max = field.get("max").and_then(|m| m.as_f64());
+            if min.is_some_and(|m| val < m) || max.is_some_and(|m| val > m) {
+                warn!("Field {}: value {} out of range [{}, {}], dropped", field_name, val,
+                    min.map_or("-".to_string(), |m| m.to_string()), max.map_or("-".to_string(), |m| m.to_string()));
+                result_js.remove(field_name);
+                return;
+            }
+        }
+        // lookup of value names ("values": { "0": "off", "1": "heating" }),
+        // values not listed stay numbers
+        if let Some(name) = result_js.get(field_name).and_then(|v| value_name(field, v)) {
+            result_js.insert(field_name.to_string(), name);
+        }
+    }
+
+    // (message position, decoded fields) of every message matching telegram
+    fn decode_matching(&self, req: &EbusRequest, resp: Option<&EbusResponse>) -> Vec<(usize, Option<serde_json::Map<String, serde_json::Value>>)> {
+        // frames with CRC error are never decoded
+        if !req.crc_valid() || resp.is_some_and(|r| !r.crc_valid()) {
+            return Vec::new();
+        }
+        // only messages which can match pbsb are checked
+        self.index.candidates(req.pbsb()).into_iter()
+            .filter(|i| Mapper::matches(self.message(*i).1, req))
+            .map(|i| (i, self.decode_message(self.message(i).1, req, resp)))
+            .collect()
+    }
+
+    // decoded fields of all messages matching telegram, without printing or publishing:
+    // [{ "circuit": ..., "message": ..., "fields": { ... } }], None when nothing was decoded
+    pub fn decode(&self, req: &EbusRequest, resp: Option<&EbusResponse>) -> Option<serde_json::Value> {
+        let decoded: Vec<serde_json::Value> = self.decode_matching(req, resp).into_iter()
+            .filter_map(|(i, fields)| {
+                let (circuit_name, msg) = self.message(i);
+                Some(serde_json::json!({ "circuit": circuit_name, "message": msg["comment"], "fields": fields? }))
+            })
+            .collect();
+        if decoded.is_empty() { None } else { Some(serde_json::Value::Array(decoded)) }
+    }
+
+    pub fn received_telegram(&mut self, req: &EbusRequest, resp: Option<&EbusResponse>) {
+        if !self.options.telegram_wanted(req) {
+            return;
+        }
+        let text = self.options.output == OutputMode::Text;
+        if text {
+            println!("Received telegram {}", req);
+            if let Some(r) = resp {
+                println!("    `-> Response: {}", r);
+            }
+        }
+        // decoded messages, for jsonl output
+        let mut matches = Vec::new();
+        let decoded = self.decode_matching(req, resp);
+        let matched = !decoded.is_empty();
+        for (i, result_js) in decoded {
+            // not through self.message(), last_published is borrowed mutably below
+            let (d, c, m) = self.index.messages[i];
+            let circuit = &self.defs[d]["circuits"][c];
+            let (circuit_name, msg) = (circuit["name"].as_str().unwrap(), &circuit["messages"][m]);
+            let Some(mut result_js) = result_js else {
+                continue;
+            };
+            if text {
+                for (field, _) in field_maps(msg, req, resp) {
+                    println!{"                Field: {} @{:02x} t={} f={} [{}]", field["field_name"].as_str().unwrap(), field["field_offset"].as_u64().unwrap(),
+                        field["data_type"].as_str().unwrap(), Scale::of(field),
+                        field_unit(field)};
+                }
+            }
+            if let Some(metrics) = &self.metrics {
+                for (field_name, value) in &result_js {
+                    // with include_units the number is wrapped, names and text are skipped
+                    let value = value.get("value").unwrap_or(value);
+                    if let Some(v) = value.as_f64().or(value.as_bool().map(|b| b as u8 as f64)) {
+                        metrics.set_value(circuit_name, msg["comment"].as_str().unwrap_or(&req.pbsb_hex()), field_name, v);
+                    }
+                }
+            }
+            // friendly names of telegram addresses, when configured
+            if address::has_names() {
+                result_js.insert("src_name".to_string(), address::display_name(req.src()).into());
+                result_js.insert("dest_name".to_string(), address::display_name(req.dest()).into());
+            }
+            // print result_js
+            if text && self.options.nested_output {
+                let message_name = msg["comment"].as_str().unwrap_or("");
+                println!("                Result: {}", serde_json::json!({ circuit_name: { message_name: &result_js } }));
+            } else if text {
+                println!("                Result: {}", serde_json::to_string(&result_js).unwrap());
+            }
+            if self.options.output == OutputMode::Jsonl {
+                matches.push(serde_json::json!({
+                    "circuit": circuit_name,
+                    "message": msg["comment"],
+                    "fields": result_js.clone(),
+                }));
+            }
+            if self.options.state_file.is_some() {
+                let circuit_state = self.state.entry(circuit_name).or_insert(serde_json::json!({}));
+                circuit_state[msg["comment"].as_str().unwrap_or("")] = result_js.clone().into();
+                self.state_dirty = true;
+            }
+
+            if let Some(publisher) = &self.mqtt {
+                // message was seen, even when none of its values changed
+                if self.options.publish_last_seen {
+                    let topic = message_topic(publisher, circuit_name, msg, self.options.nested_output);
+                    let topic = if topic.contains("{field}") { topic.replace("{field}", "last_seen") } else { format!("{}/last_seen", topic) };
+                    let ts = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+                    publisher.publish_qos(&topic, ts, publisher.qos(), self.options.retain);
+                }
+                if self.options.publish_on_change {
+                    let changed = changed_fields(&mut self.last_published, self.options.max_staleness, circuit_name, msg, &result_js);
+                    if changed.is_empty() {
+                        continue;
+                    }
+                    // per-field topics publish only what changed, json object is published whole
+                    if topic_template(msg, self.options.nested_output).contains("{field}") {
+                        result_js.retain(|name, _| changed.contains(name));
+                    }
+                }
+                if self.options.include_timing {
+                    insert_timing(&mut result_js, req, resp);
+                }
+                if let Some(acked) = req.acked().filter(|_| self.options.include_acked) {
+                    result_js.insert("acked".to_string(), acked.into());
+                }
+                if let Some(interval) = self.options.min_publish_interval {
+                    let topic = message_topic(publisher, circuit_name, msg, self.options.nested_output);
+                    let throttle = self.throttled.entry(topic).or_insert(Throttle { last: None, pending: None });
+                    if throttle.last.is_some_and(|t| t.elapsed() < interval) {
+                        // newer values replace older ones, published by publish_throttled
+                        throttle.pending.get_or_insert_with(|| (i, serde_json::Map::new())).1.extend(result_js);
+                        continue;
+                    }
+                    if let Some((_, mut pending)) = throttle.pending.take() {
+                        pending.extend(result_js);
+                        result_js = pending;
+                    }
+                    throttle.last = Some(Instant::now());
+                }
+                publish_result(publisher, circuit_name, msg, result_js, self.options.nested_output, self.options.retain);
+            }
+        }
+        self.publish_throttled();
+        if self.state_dirty && self.state_saved.elapsed() >= STATE_SAVE_INTERVAL {
+            self.save_state();
+        }
+        let crc_valid = req.crc_valid() && resp.is_none_or(|r| r.crc_valid());
+        if !matched && crc_valid {
+            self.report_unmatched(req, resp);
+        }
+        if self.options.output == OutputMode::Jsonl {
+            let mut line = serde_json::json!({
+                "src": req.src_hex(),
+                "dest": req.dest_hex(),
+                "pbsb": req.pbsb_hex(),
+                "data": req.data_hex(),
+                "messages": matches,
+            });
+            if let Some(r) = resp {
+                line["response"] = r.data_hex().into();
+            }
+            if let Some(acked) = req.acked() {
+                line["acked"] = acked.into();
+            }
+            // received vs computed CRC of the part which failed
+            if !crc_valid {
+                let (crc, computed) = match resp.filter(|r| !r.crc_valid()) {
+                    Some(r) => (r.crc(), r.computed_crc()),
+                    None => (req.crc(), req.computed_crc())
+                };
+                line["crc_valid"] = false.into();
+                line["crc"] = format!("{:02X}", crc).into();
+                line["crc_computed"] = format!("{:02X}", computed).into();
+            }
+            if let serde_json::Value::Object(obj) = &mut line {
+                insert_timing(obj, req, resp);
+            }
+            println!("{}", line);
+        }
+    }
+}
+
+/*
+    Message topic template ("topic" key of message definition), placeholders:
+    {base}      - mqtt.topic from config.json
+    {circuit}   - circuit name
+    {message}   - message comment
+    {field}     - field name, each field is then published separately as plain value
+    Default is "{base}/{circuit}/{message}", publishing all fields as one json object,
+    or "{base}/{circuit}/{message}/{field}" with nested_output.
+ */
+const DEFAULT_TOPIC: &str = "{base}/{circuit}/{message}";
+const NESTED_TOPIC: &str = "{base}/{circuit}/{message}/{field}";
+
+fn topic_template(msg: &serde_json::Value, nested: bool) -> &str {
+    msg["topic"].as_str().unwrap_or(if nested { NESTED_TOPIC } else { DEFAULT_TOPIC })
+}
+
+// message topic, with {field} placeholder left unresolved
+fn message_topic(publisher: &MqttPublisher, circuit_name: &str, msg: &serde_json::Value, nested: bool) -> String {
+    topic(publisher.base_topic(), circuit_name, msg, nested)
+}
+
+// topic of message below base_topic, {field} is left in for per-field topics
+pub fn topic(base_topic: &str, circuit_name: &str, msg: &serde_json::Value, nested: bool) -> String {
+    topic_template(msg, nested)
+        .replace("{base}", base_topic)
+        .replace("{circuit}", &mqtt::topic_segment(circuit_name))
+        .replace("{message}", &mqtt::topic_segment(msg["comment"].as_str().unwrap_or("")))
+}
+
+// field definitions with data they apply to, request_map fields on request data
+// followed by response_map fields on response data (when there is a response,
+// never for broadcasts)
+fn field_maps<'a>(msg: &'a serde_json::Value, req: &'a EbusRequest, resp: Option<&'a EbusResponse>) -> Vec<(&'a serde_json::Value, &'a Vec<u8>)> {
+    let request = msg["request_map"].as_array().into_iter().flatten().map(|f| (f, req.data()));
+    let response = resp.filter(|_| req.kind() != TelegramKind::Broadcast).into_iter()
+        .flat_map(|r| msg["response_map"].as_array().into_iter().flatten().map(move |f| (f, r.data())));
+    request.chain(response).collect()
+}
+
+// frame completion time, response latency (when there was a response) and
+// bus idle time before the frame in milliseconds
+fn insert_timing(result_js: &mut serde_json::Map<String, serde_json::Value>, req: &EbusRequest, resp: Option<&EbusResponse>) {
+    result_js.insert("ts".to_string(), humantime::format_rfc3339_millis(req.timestamp()).to_string().into());
+    if let Some(latency) = resp.and_then(|r| r.latency()) {
+        result_js.insert("response_latency_ms".to_string(), serde_json::json!(latency.as_secs_f64() * 1000.0));
+    }
+    if let Some(gap) = req.gap() {
+        result_js.insert("frame_gap_ms".to_string(), serde_json::json!(gap.as_secs_f64() * 1000.0));
+    }
+}
+
+// returns names of fields that changed (or went stale) since last publish
+// and remembers their values as published
+fn changed_fields(cache: &mut HashMap<String, (serde_json::Value, Instant)>, max_staleness: Option<Duration>,
+                  circuit_name: &str, msg: &serde_json::Value, result_js: &serde_json::Map<String, serde_json::Value>) -> Vec<String> {
+    let now = Instant::now();
+    let message_name = msg["comment"].as_str().unwrap_or("");
+    let mut changed = Vec::new();
+    for (field_name, value) in result_js {
+        let key = format!("{}/{}/{}", circuit_name, message_name, field_name);
+        let fresh = cache.get(&key).is_some_and(|(last, at)| {
+            last == value && max_staleness.is_none_or(|max| now.duration_since(*at) < max)
+        });
+        if !fresh {
+            cache.insert(key, (value.clone(), now));
+            changed.push(field_name.clone());
+        }
+    }
+    changed
+}
+
+fn publish_result(publisher: &MqttPublisher, circuit_name: &str, msg: &serde_json::Value, result_js: serde_json::Map<String, serde_json::Value>,
+    nested: bool, retain: bool) {
+    let topic = message_topic(publisher, circuit_name, msg, nested);
+    // field "qos" overrides mqtt.qos, json object goes with highest qos of its fields
+    let field_qos = |field_name: &str| {
+        ["request_map", "response_map"].iter()
+            .filter_map(|m| msg[*m].as_array())
+            .flatten()
+            .find(|f| f["field_name"] == field_name)
+            .and_then(|f| f["qos"].as_u64())
+            .map_or(publisher.qos(), |q| q as u8)
+    };
+
+    if topic.contains("{field}") {
+        for (field_name, value) in result_js {
+            let field_topic = topic.replace("{field}", &mqtt::topic_segment(&field_name));
+            let payload = match value {
+                serde_json::Value::String(text) => text,
+                _ => value.to_string()
+            };
+            publisher.publish_qos(&field_topic, payload, field_qos(&field_name), retain);
+        }
+    } else {
+        let qos = result_js.keys().map(|name| field_qos(name)).max().unwrap_or(publisher.qos());
+        publisher.publish_qos(&topic, serde_json::Value::Object(result_js).to_string(), qos, retain);
+    }
+}
+
+// request telegram (dest, pbsb, data) writing payload to message, the reverse
+// of decoding its request_map: payload is a json object of field values, or a
+// bare value when message has a single request field, data starts with the
+// "^<hex>" prefix of request_match data
+pub fn encode_request(msg: &serde_json::Value, payload: &serde_json::Value) -> Result<(u8, u16, Vec<u8>), String> {
+    let request_match = &msg["request_match"];
+    let dest = request_match["dst"].as_str().filter(|d| d.len() == 2)
+        .and_then(|d| u8::from_str_radix(d, 16).ok())
+        .ok_or("request_match dst is not a single address")?;
+    let pbsb = match exact_pbsb(request_match).as_deref() {
+        Some([pbsb]) => *pbsb,
+        _ => return Err("request_match has no single pbsb".to_string()),
+    };
+    let mut data = match request_match["data"].as_str() {
+        Some("*") => Vec::new(),
+        Some(p) => hex::decode(p.strip_prefix('^').unwrap_or(p)).map_err(|_| format!("request_match data {} is not a fixed prefix", p))?,
+        None => return Err("request_match data is not a single pattern".to_string()),
+    };
+    let fields = msg["request_map"].as_array().map_or(&[][..], |f| f.as_slice());
+    if fields.is_empty() {
+        return Err("message has no request_map".to_string());
+    }
+    if let Some(name) = payload.as_object().and_then(|values| values.keys().find(|k| !fields.iter().any(|f| f["field_name"] == **k))) {
+        return Err(format!("unknown field {}", name));
+    }
+    for field in fields {
+        let field_name = field["field_name"].as_str().unwrap();
+        let value = match payload.as_object() {
+            Some(values) => values.get(field_name).ok_or_else(|| format!("{} missing", field_name))?,
+            None if fields.len() == 1 => payload,
+            None => return Err(format!("json object of {} fields expected", fields.len())),
+        };
+        let bytes = encode_value(field, value).map_err(|e| format!("{}: {}", field_name, e))?;
+        let offset = field["field_offset"].as_u64().unwrap() as usize;
+        if data.len() < offset + bytes.len() {
+            data.resize(offset + bytes.len(), 0);
+        }
+        data[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+    Ok((dest, pbsb, data))
+}
+
+// bytes of field value, inverse of decode_field: value names are looked up
+// in "values", offset and factor / divisor are undone before conversion
+fn encode_value(field: &serde_json::Value, value: &serde_json::Value) -> Result<Vec<u8>, String> {
+    let val = match value {
+        serde_json::Value::String(name) if field.get("values").is_some() => field["values"].as_object()
+            .and_then(|values| values.iter().find(|(_, n)| *n == name))
+            .and_then(|(key, _)| key.parse::<f64>().ok())
+            .ok_or_else(|| format!("unknown value {}", name))?,
+        _ => value.as_f64().ok_or_else(|| format!("{} is not a number", value))?,
+    };
+    let val = val - field["offset"].as_f64().unwrap_or(0.0);
+    let data_type = field["data_type"].as_str().unwrap();
+    // divisor is undone by multiplying, exact unlike its factor
+    let (val, factor) = match Scale::of(field) {
+        Scale::Factor(factor) => (val, factor),
+        Scale::Divisor(divisor) => (val * divisor, 1.0),
+    };
+    match data_type {
+        // width and byte order of generic integers are given by the field
+        "uint" | "int" => {
+            let bits = 8 * field["bytes"].as_u64().unwrap_or(1) as u32;
+            let (min, max) = if data_type == "int" { (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1) } else { (0, (1i64 << bits) - 1) };
+            int_bytes(val / factor, min, max, bits as usize / 8, field["endian"].as_str() == Some("be"))
+        }
+        _ => encode_field(val, data_type, factor)
+    }
+}
+
+// bytes of value as data_type scaled by factor, inverse of decoding: raw value
+// is value / factor rounded to the nearest integer, error when it doesn't fit
+// data_type (eBUS replacement values like DATA2b 0x8000 are never produced)
+pub fn encode_field(value: f64, data_type: &str, factor: f64) -> Result<Vec<u8>, String> {
+    let raw = value / factor;
+    match data_type {
+        "u8" => int_bytes(raw, 0, 0xFF, 1, false),
+        "s8" => int_bytes(raw, -0x80, 0x7F, 1, false),
+        "u16le" | "u16he" => int_bytes(raw, 0, 0xFFFF, 2, data_type == "u16he"),
+        "s16le" | "s16he" => int_bytes(raw, -0x8000, 0x7FFF, 2, data_type == "s16he"),
+        "u24le" | "u24he" => int_bytes(raw, 0, 0xFF_FFFF, 3, data_type == "u24he"),
+        "u32le" | "u32he" => int_bytes(raw, 0, 0xFFFF_FFFF, 4, data_type == "u32he"),
+        "DATA1b" => int_bytes(raw, -0x7F, 0x7F, 1, false),
+        "DATA1c" => int_bytes(raw * 2.0, 0, 0xFE, 1, false),
+        "DATA2b" => int_bytes(raw * 256.0, -0x7FFF, 0x7FFF, 2, false),
+        "DATA2c" => int_bytes(raw * 16.0, -0x7FFF, 0x7FFF, 2, false),
+        "percent0" => int_bytes(raw * 255.0 / 100.0, 0, 0xFF, 1, false),
+        "percent_half" => int_bytes(raw * 2.0, 0, 0xC8, 1, false),
+        // packed BCD, bcd16 low byte first
+        "bcd" | "bcd16" => {
+            let width = if data_type == "bcd" { 1 } else { 2 };
+            let max = if data_type == "bcd" { 99 } else { 9999 };
+            let val = int_bytes(raw, 0, max, 4, false).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))?;
+            Ok((0..width).map(|i| {
+                let pair = (val / 100u32.pow(i as u32)) % 100;
+                (((pair / 10) << 4) | (pair % 10)) as u8
+            }).collect())
+        }
+        _ => Err(format!("data type {} can't be written", data_type))
+    }
+}
+
+// raw value rounded to integer of width bytes, two's complement for negatives
+fn int_bytes(raw: f64, min: i64, max: i64, width: usize, big_endian: bool) -> Result<Vec<u8>, String> {
+    let rounded = raw.round();
+    if !rounded.is_finite() || rounded < min as f64 || rounded > max as f64 {
+        return Err(format!("raw value {} out of range {}..={}", raw, min, max));
+    }
+    let mut bytes = (rounded as i64).to_le_bytes()[..width].to_vec();
+    if big_endian {
+        bytes.reverse();
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // single message of the given fields matching any B505 request to 03
+    fn definitions(map: &str, fields: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({ "appliance": "test", "bus": "ebus", "circuits": [{ "name": "test", "messages": [{
+            "comment": "test",
+            "request_match": { "src": "*", "dst": "03", "pbsb": "B505", "data": "*" },
+            map: fields
+        }] }] })
+    }
+
+    fn mapper(defs: serde_json::Value, options: MapperOptions) -> Mapper {
+        assert_eq!(validate_definitions(&defs), Vec::<String>::new());
+        Mapper::new(vec![defs], None, MapperOptions { output: OutputMode::None, ..options })
+    }
+
+    // fields decoded from request data by a request_map of the given fields
+    fn decode_with(options: MapperOptions, fields: serde_json::Value, data: &[u8]) -> serde_json::Value {
+        let mapper = mapper(definitions("request_map", fields), options);
+        mapper.decode(&EbusRequest::new(0x10, 0x03, 0xB505, data), None).map_or(serde_json::Value::Null, |d| d[0]["fields"].clone())
+    }
+
+    // value of field "value" of data_type decoded from data, Null when dropped
+    fn decode_type(field: serde_json::Value, data: &[u8]) -> serde_json::Value {
+        let mut field = field;
+        field["field_name"] = "value".into();
+        field["field_offset"] = field.get("field_offset").cloned().unwrap_or(0.into());
+        decode_with(MapperOptions::default(), serde_json::json!([field]), data)["value"].clone()
+    }
+
+    fn assert_close(value: &serde_json::Value, expected: f64) {
+        assert!(value.as_f64().is_some_and(|v| (v - expected).abs() < 1e-9), "{} is not {}", value, expected);
+    }
+
+    #[test]
+    fn percent_types_scale_and_clamp() {
+        let percent0 = serde_json::json!({ "data_type": "percent0" });
+        let percent_half = serde_json::json!({ "data_type": "percent_half" });
+        assert_close(&decode_type(percent0.clone(), &[0x7F]), 127.0 * 100.0 / 255.0);
+        assert_close(&decode_type(percent0, &[0xFF]), 100.0);
+        assert_close(&decode_type(percent_half.clone(), &[0x7F]), 63.5);
+        // 0xFF is 127.5%
+        assert_close(&decode_type(percent_half, &[0xFF]), 100.0);
+        assert_eq!(field_unit(&serde_json::json!({ "data_type": "percent0" })), "%");
+    }
+
+    // encode_field checked against decoding, (data type, factor, values)
+    const ROUNDTRIP: &[(&str, f64, &[f64])] = &[
+        ("u8", 1.0, &[0.0, 42.0, 255.0]),
+        ("s8", 1.0, &[-128.0, -1.0, 127.0]),
+        ("u16le", 0.1, &[0.0, 21.5, 6553.5]),
+        ("u16he", 1.0, &[0.0, 4660.0, 65535.0]),
+        ("s16le", 0.01, &[-327.68, -0.5, 327.67]),
+        ("s16he", 1.0, &[-32768.0, 0.0, 32767.0]),
+        ("u24le", 1.0, &[0.0, 70000.0, 16777215.0]),
+        ("u24he", 0.5, &[0.0, 8388607.5]),
+        ("u32le", 1.0, &[0.0, 4294967295.0]),
+        ("u32he", 0.001, &[0.0, 123456.789]),
+        ("DATA1b", 1.0, &[-127.0, -5.0, 0.0, 127.0]),
+        ("DATA1c", 1.0, &[0.0, 36.5, 127.0]),
+        ("DATA2b", 1.0, &[-40.25, 0.0, 99.99]),
+        ("DATA2c", 1.0, &[-12.5, 53.0625]),
+        ("bcd", 1.0, &[0.0, 42.0, 99.0]),
+        ("bcd16", 1.0, &[0.0, 1234.0, 9999.0]),
+        ("percent0", 1.0, &[0.0, 50.0, 100.0]),
+        ("percent_half", 1.0, &[0.0, 63.5, 100.0]),
+    ];
+
+    // decoded value step of data type, before factor
+    fn resolution(data_type: &str) -> f64 {
+        match data_type {
+            "DATA1c" | "percent_half" => 0.5,
+            "percent0" => 100.0 / 255.0,
+            "DATA2b" => 1.0 / 256.0,
+            "DATA2c" => 1.0 / 16.0,
+            _ => 1.0
+        }
+    }
+
+    // every value has to decode back within half a step of its type
+    #[test]
+    fn encode_field_roundtrip() {
+        for (data_type, factor, values) in ROUNDTRIP {
+            for value in values.iter() {
+                let bytes = encode_field(*value, data_type, *factor).unwrap_or_else(|e| panic!("{} {}: {}", data_type, value, e));
+                let decoded = decode_type(serde_json::json!({ "data_type": data_type, "factor": factor }), &bytes);
+                let decoded = decoded.as_f64().unwrap_or_else(|| panic!("{} {}: decoded {}", data_type, value, decoded));
+                assert!((decoded - value).abs() <= resolution(data_type) * factor / 2.0 + 1e-9, "{} {} -> {}", data_type, value, decoded);
+            }
+        }
+    }
+
+    #[test]
+    fn encode_field_range_checked() {
+        assert!(encode_field(256.0, "u8", 1.0).is_err());
+        assert!(encode_field(-1.0, "u16le", 1.0).is_err());
+        assert!(encode_field(12.8, "s8", 0.1).is_err());
+        // replacement value 0x80 is not a number
+        assert!(encode_field(-128.0, "DATA1b", 1.0).is_err());
+        assert!(encode_field(100.0, "bcd", 1.0).is_err());
+        assert!(encode_field(f64::NAN, "u8", 1.0).is_err());
+        assert!(encode_field(1.0, "f32le", 1.0).is_err());
+    }
+
+    // uint / int take width and byte order from the field definition
+    #[test]
+    fn encode_generic_int_roundtrip() {
+        let fields = [
+            (serde_json::json!({ "data_type": "uint", "bytes": 3 }), vec![0.0, 0x123456 as f64, 16777215.0]),
+            (serde_json::json!({ "data_type": "uint", "bytes": 2, "endian": "be", "divisor": 10 }), vec![0.0, 6553.5]),
+            (serde_json::json!({ "data_type": "int", "bytes": 1 }), vec![-128.0, 127.0]),
+            (serde_json::json!({ "data_type": "int", "bytes": 4, "endian": "be", "offset": -40 }), vec![-2147483688.0, -40.0, 2147483607.0]),
+        ];
+        for (field, values) in fields {
+            for value in values {
+                let bytes = encode_value(&field, &value.into()).unwrap_or_else(|e| panic!("{} {}: {}", field, value, e));
+                assert_eq!(bytes.len() as u64, field["bytes"].as_u64().unwrap());
+                assert_close(&decode_type(field.clone(), &bytes), value);
+            }
+        }
+        assert!(encode_value(&serde_json::json!({ "data_type": "int", "bytes": 1 }), &128.into()).is_err());
+        assert_eq!(encode_value(&serde_json::json!({ "data_type": "uint", "bytes": 2, "endian": "be" }), &0x1234.into()), Ok(vec![0x12, 0x34]));
+    }
+
+    #[test]
+    fn ebus_data_types_decode() {
+        // low byte first, 0x0F00 / 16
+        assert_close(&decode_type(serde_json::json!({ "data_type": "DATA2c" }), &[0x00, 0x0F]), 240.0);
+        assert_close(&decode_type(serde_json::json!({ "data_type": "DATA2c" }), &[0xF8, 0xFF]), -0.5);
+        assert_close(&decode_type(serde_json::json!({ "data_type": "DATA2b" }), &[0x80, 0x15]), 21.5);
+        assert_close(&decode_type(serde_json::json!({ "data_type": "DATA1c" }), &[0x49]), 36.5);
+        assert_close(&decode_type(serde_json::json!({ "data_type": "DATA1b" }), &[0xFB]), -5.0);
+    }
+
+    #[test]
+    fn ebus_replacement_values_are_null() {
+        for (data_type, data) in [("DATA1b", &[0x80][..]), ("DATA1c", &[0xFF]), ("DATA2b", &[0x00, 0x80]), ("DATA2c", &[0x00, 0x80])] {
+            let fields = serde_json::json!([{ "field_name": "value", "field_offset": 0, "data_type": data_type }]);
+            assert_eq!(decode_with(MapperOptions::default(), fields, data), serde_json::json!({ "value": null }), "{}", data_type);
+        }
+    }
+
+    #[test]
+    fn bcd_decodes_digits() {
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "bcd" }), &[0x42]), 42);
+        // bcd16 is low byte first
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "bcd16" }), &[0x34, 0x12]), 1234);
+    }
+
+    #[test]
+    fn bcd_nibble_over_nine_drops_field() {
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "bcd" }), &[0x4A]), serde_json::Value::Null);
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "bcd16" }), &[0x34, 0xF2]), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn f32_decodes_both_byte_orders() {
+        // 1234.5 is 0x449A5000
+        assert_close(&decode_type(serde_json::json!({ "data_type": "f32le" }), &[0x00, 0x50, 0x9A, 0x44]), 1234.5);
+        assert_close(&decode_type(serde_json::json!({ "data_type": "f32be", "factor": 0.1 }), &[0x44, 0x9A, 0x50, 0x00]), 123.45);
+    }
+
+    #[test]
+    fn f32_past_end_of_data_is_skipped() {
+        let fields = serde_json::json!([
+            { "field_name": "first", "field_offset": 0, "data_type": "u8" },
+            { "field_name": "energy", "field_offset": 1, "data_type": "f32le" }
+        ]);
+        assert_eq!(decode_with(MapperOptions::default(), fields, &[0x01, 0x00, 0x50, 0x9A]), serde_json::json!({ "first": 1 }));
+    }
+
+    #[test]
+    fn field_offset_beyond_data_is_skipped() {
+        let fields = serde_json::json!([
+            { "field_name": "first", "field_offset": 0, "data_type": "u8" },
+            { "field_name": "far", "field_offset": 8, "data_type": "u8" },
+            { "field_name": "text", "field_offset": 1, "data_type": "ascii" }
+        ]);
+        assert_eq!(decode_with(MapperOptions::default(), fields.clone(), &[0x01]), serde_json::json!({ "first": 1 }));
+        // nothing within data drops the message
+        assert_eq!(decode_with(MapperOptions::default(), fields, &[]), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn bit_extracts_flag() {
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "bit", "bit_offset": 3 }), &[0x08]), true);
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "bit", "bit_offset": 2 }), &[0x08]), false);
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "bit" }), &[0x01]), true);
+    }
+
+    #[test]
+    fn bits_extracts_range() {
+        // bits 4..7 of 0xA5
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "bits", "bit_offset": 4, "bit_width": 3 }), &[0xA5]), 2);
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "bits", "bit_offset": 0, "bit_width": 8 }), &[0xA5]), 0xA5);
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "bits", "bit_offset": 6, "bit_width": 3 }), &[0xA5]), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn range_pattern_bounds() {
+        let range = serde_json::json!("[03-0A]");
+        // lower and upper bound are inclusive
+        assert!(match_field("03", &range));
+        assert!(match_field("0a", &range));
+        assert!(match_field("07", &range));
+        // only the first byte counts
+        assert!(match_field("0AFF", &range));
+        assert!(!match_field("02", &range));
+        assert!(!match_field("0B", &range));
+        assert!(!match_field("FE", &range));
+        assert!(!match_field("", &range));
+        assert!(!match_field("05", &serde_json::json!("[03-ZZ]")));
+    }
+
+    #[test]
+    fn other_patterns_keep_working() {
+        assert!(match_field("B509", &serde_json::json!("*")));
+        assert!(match_field("B509", &serde_json::json!("^B5")));
+        assert!(match_field("B509", &serde_json::json!("B5*9")));
+        assert!(match_field("B509", &serde_json::json!(["B510", "[B0-B5]"])));
+        assert!(!match_field("B509", &serde_json::json!("B50")));
+        assert!(!match_field("B509", &serde_json::json!(null)));
+        assert!(!match_field("B509", &serde_json::json!([1, 2])));
+        assert!(match_field("B509", &serde_json::json!([1, "B509"])));
+    }
+
+    #[test]
+    fn u32_and_u24_decode_with_factor() {
+        let data = [0x78, 0x56, 0x34, 0x12];
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "u32le" }), &data), 0x12345678);
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "u32he" }), &data), 0x78563412u32);
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "u24le" }), &data), 0x345678);
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "u24he", "field_offset": 1 }), &data), 0x563412);
+        // 305419896 Wh in kWh
+        assert_close(&decode_type(serde_json::json!({ "data_type": "u32le", "factor": 0.001 }), &data), 305419.896);
+        assert_close(&decode_type(serde_json::json!({ "data_type": "u24le", "factor": 0.1 }), &data), 343000.8);
+        // short data drops the field
+        assert_eq!(decode_type(serde_json::json!({ "data_type": "u32le", "field_offset": 1 }), &data), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn decimals_round_published_value() {
+        assert_eq!(round_value(&serde_json::json!(44.99999), 1), Some(serde_json::json!(45.0)));
+        assert_eq!(round_value(&serde_json::json!(45), 1), None);
+        // 4499999 * 0.00001 = 44.99999
+        let field = serde_json::json!({ "data_type": "u32le", "factor": 0.00001, "decimals": 1 });
+        assert_eq!(decode_type(field, &4499999u32.to_le_bytes()), serde_json::json!(45.0));
+        // global decimals, overridden by field
+        let fields = serde_json::json!([
+            { "field_name": "global", "field_offset": 0, "data_type": "u32le", "factor": 0.00001 },
+            { "field_name": "field", "field_offset": 0, "data_type": "u32le", "factor": 0.00001, "decimals": 3 }
+        ]);
+        let options = MapperOptions { decimals: Some(0), ..Default::default() };
+        assert_eq!(decode_with(options, fields, &4412345u32.to_le_bytes()), serde_json::json!({ "global": 44.0, "field": 44.123 }));
+    }
+
+    #[test]
+    fn pbsb_prefix_pattern() {
+        let mut defs = definitions("request_map", serde_json::json!([{ "field_name": "value", "field_offset": 0, "data_type": "u8" }]));
+        defs["circuits"][0]["messages"][0]["request_match"]["pbsb"] = "^10".into();
+        let mapper = mapper(defs, MapperOptions::default());
+        let decoded = |pbsb: u16| mapper.decode(&EbusRequest::new(0x10, 0x03, pbsb, &[0x01]), None).is_some();
+        assert!(decoded(0x1005));
+        assert!(decoded(0x10FF));
+        assert!(!decoded(0x0510));
+        // zero padded, "0010" instead of "10"
+        assert!(!decoded(0x0010));
+    }
+
+    #[test]
+    fn request_and_response_map_fields_merged() {
+        let mut defs = definitions("request_map", serde_json::json!([{ "field_name": "circuit", "field_offset": 0, "data_type": "u8" }]));
+        defs["circuits"][0]["messages"][0]["response_map"] = serde_json::json!([
+            { "field_name": "temp", "field_offset": 0, "data_type": "DATA2c" },
+            { "field_name": "state", "field_offset": 2, "data_type": "u8" }
+        ]);
+        let mapper = mapper(defs, MapperOptions::default());
+        let req = EbusRequest::new(0x10, 0x03, 0xB505, &[0x02]);
+        let resp = EbusResponse::new(&[0x50, 0x01, 0x03]);
+        let decoded = mapper.decode(&req, Some(&resp)).unwrap();
+        assert_eq!(decoded[0]["fields"], serde_json::json!({ "circuit": 2, "temp": 21.0, "state": 3 }));
+        // request fields alone without response
+        assert_eq!(mapper.decode(&req, None).unwrap()[0]["fields"], serde_json::json!({ "circuit": 2 }));
+    }
+
+    fn broadcast_message(maps: serde_json::Value) -> serde_json::Value {
+        let mut msg = serde_json::json!({
+            "comment": "date time",
+            "kind": "broadcast",
+            "request_match": { "src": "*", "dst": "FE", "pbsb": "0700", "data": "*" }
+        });
+        msg.as_object_mut().unwrap().extend(maps.as_object().unwrap().clone());
+        serde_json::json!({ "appliance": "test", "bus": "ebus", "circuits": [{ "name": "heater", "messages": [msg] }] })
+    }
+
+    #[test]
+    fn broadcast_decoded_by_request_map() {
+        let defs = broadcast_message(serde_json::json!({
+            "request_map": [
+                { "field_name": "outside_temp", "field_offset": 0, "data_type": "DATA2b" },
+                { "field_name": "time", "field_offset": 2, "data_type": "bti" },
+                { "field_name": "date", "field_offset": 5, "data_type": "bda" }
+            ],
+            // ignored, broadcasts are never answered
+            "response_map": [{ "field_name": "ignored", "field_offset": 0, "data_type": "u8" }]
+        }));
+        let mapper = mapper(defs, MapperOptions::default());
+        let req = EbusRequest::new(0x10, 0xFE, 0x0700, &[0x80, 0x0A, 0x30, 0x15, 0x08, 0x14, 0x10, 0x03, 0x26]);
+        assert_eq!(req.kind(), TelegramKind::Broadcast);
+        let fields = serde_json::json!({ "outside_temp": 10.5, "time": "08:15:30", "date": "2026-10-14" });
+        assert_eq!(mapper.decode(&req, None), Some(serde_json::json!([{ "circuit": "heater", "message": "date time", "fields": fields }])));
+        assert_eq!(mapper.decode(&req, Some(&EbusResponse::new(&[0x01]))).unwrap()[0]["fields"], fields);
+    }
+
+    #[test]
+    fn broadcast_with_response_map_only_is_rejected() {
+        let defs = broadcast_message(serde_json::json!({
+            "response_map": [{ "field_name": "value", "field_offset": 0, "data_type": "u8" }]
+        }));
+        assert_eq!(validate_definitions(&defs).len(), 1);
+    }
+
+    #[test]
+    fn request_match_len_selects_message() {
+        let message = |comment: &str, len: serde_json::Value, data_type: &str| serde_json::json!({
+            "comment": comment,
+            "request_match": { "src": "*", "dst": "03", "pbsb": "B505", "data": "*", "len": len },
+            "request_map": [{ "field_name": comment, "field_offset": 0, "data_type": data_type }]
+        });
+        let defs = serde_json::json!({ "appliance": "test", "bus": "ebus", "circuits": [{ "name": "heater", "messages": [
+            message("setpoint", 1.into(), "u8"),
+            message("boost", 2.into(), "u16le"),
+            message("any", "*".into(), "u8"),
+        ] }] });
+        let mapper = mapper(defs, MapperOptions::default());
+        let messages = |data: &[u8]| -> Vec<serde_json::Value> {
+            mapper.decode(&EbusRequest::new(0x10, 0x03, 0xB505, data), None).unwrap().as_array().unwrap()
+                .iter().map(|m| m["fields"].clone()).collect()
+        };
+        assert_eq!(messages(&[0xAA]), vec![serde_json::json!({ "setpoint": 170 }), serde_json::json!({ "any": 170 })]);
+        assert_eq!(messages(&[0x2D, 0x00]), vec![serde_json::json!({ "boost": 45 }), serde_json::json!({ "any": 45 })]);
+        assert_eq!(messages(&[0x01, 0x02, 0x03]), vec![serde_json::json!({ "any": 1 })]);
+    }
+
+    #[test]
+    fn request_match_len_is_validated() {
+        let mut defs = definitions("request_map", serde_json::json!([{ "field_name": "value", "field_offset": 0, "data_type": "u8" }]));
+        for len in [serde_json::json!("1"), serde_json::json!(-1), serde_json::json!(1.5)] {
+            defs["circuits"][0]["messages"][0]["request_match"]["len"] = len.clone();
+            assert_eq!(validate_definitions(&defs).len(), 1, "len {}", len);
+        }
+    }
+
+    #[test]
+    fn unnamed_message_falls_back_to_pbsb() {
+        let message = serde_json::json!({ "request_match": { "src": "*", "dst": "03", "pbsb": "B509", "data": "*" } });
+        assert_eq!(discovery_unique_id("heater", &message, "temp"), "ebus_heater_B509_temp");
+        let message = serde_json::json!({ "request_match": { "src": "*", "dst": "03", "pb": "B5", "sb": ["09", "10"], "data": "*" } });
+        assert_eq!(discovery_unique_id("heater", &message, "temp"), "ebus_heater_B509_10_temp");
+        let message = serde_json::json!({ "comment": "flow temp", "request_match": { "src": "*", "dst": "03", "pbsb": "B509", "data": "*" } });
+        assert_eq!(discovery_unique_id("heater", &message, "temp"), "ebus_heater_flow_temp_temp");
+
+        let mut defs = definitions("request_map", serde_json::json!([{ "field_name": "value", "field_offset": 0, "data_type": "u8" }]));
+        defs["circuits"][0]["messages"][0].as_object_mut().unwrap().remove("comment");
+        let mut mapper = mapper(defs, MapperOptions::default());
+        let metrics = Metrics::default();
+        mapper.set_metrics(metrics.clone());
+        mapper.received_telegram(&EbusRequest::new(0x10, 0x03, 0xB505, &[0x2A]), None);
+        assert!(metrics.render().contains("ebus_test_b505_value 42\n"), "{}", metrics.render());
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::ebus::parser::Stats;
+
+// scrapes are answered one by one, a client sending no request can hold the
+// endpoint for this long
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// parser counters and latest decoded field values, exported in Prometheus
+// text format by a small HTTP endpoint (GET /metrics)
+#[derive(Clone, Default)]
+pub struct Metrics {
+    state: Arc<Mutex<MetricsState>>,
+}
+
+#[derive(Default)]
+struct MetricsState {
+    stats: Stats,
+    // metric name -> latest value
+    values: BTreeMap<String, f64>,
+}
+
+impl Metrics {
+    // called by the receiver with counters of the current parser
+    pub fn set_stats(&self, stats: &Stats) {
+        self.state.lock().unwrap().stats = stats.clone();
+    }
+
+    pub fn set_value(&self, circuit_name: &str, message_name: &str, field_name: &str, value: f64) {
+        self.state.lock().unwrap().values.insert(metric_name(circuit_name, message_name, field_name), value);
+    }
+
+    pub fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+        // Stats fields become ebus_<counter>_total
+        if let serde_json::Value::Object(counters) = serde_json::to_value(&state.stats).unwrap() {
+            for (name, value) in counters {
+                out.push_str(&format!("# TYPE ebus_{}_total counter\nebus_{}_total {}\n", name, name, value));
+            }
+        }
+        for (name, value) in &state.values {
+            out.push_str(&format!("# TYPE {} gauge\n{} {}\n", name, name, value));
+        }
+        out
+    }
+
+    // spawns thread answering scrapes on addr (eg. "0.0.0.0:9100")
+    pub fn serve(&self, addr: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Serving metrics on http://{}/metrics", addr);
+        let metrics = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|s| metrics.answer(s));
+                if let Err(e) = result {
+                    warn!("Metrics request failed: {}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn answer(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+        stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+        let mut request_line = String::new();
+        BufReader::new(&stream).read_line(&mut request_line)?;
+        let path = request_line.split_whitespace().nth(1).unwrap_or("");
+        let (status, body) = if path == "/metrics" || path == "/" {
+            ("200 OK", self.render())
+        } else {
+            ("404 Not Found", String::new())
+        };
+        write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, body.len(), body)
+    }
+}
+
+// ebus_<circuit>_<message>_<field>, lowercase with anything but [a-z0-9_] replaced by '_'
+pub fn metric_name(circuit_name: &str, message_name: &str, field_name: &str) -> String {
+    format!("ebus_{}_{}_{}", circuit_name, message_name, field_name)
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_value_renders_gauge_per_field() {
+        let metrics = Metrics::default();
+        metrics.set_value("Heater", "flow temp", "temp", 21.5);
+        // message without comment is named by its pbsb
+        metrics.set_value("Heater", "B509", "temp", 1.0);
+        metrics.set_value("Heater", "B509", "temp", 2.0);
+        let out = metrics.render();
+        assert!(out.contains("# TYPE ebus_heater_flow_temp_temp gauge\nebus_heater_flow_temp_temp 21.5\n"), "{}", out);
+        assert!(out.contains("ebus_heater_b509_temp 2\n"), "{}", out);
+        assert!(!out.contains("ebus_heater_b509_temp 1\n"));
+    }
+}
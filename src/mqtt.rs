@@ -0,0 +1,421 @@
+use std::fs;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rumqttc::{Client, ClientError, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, Publish, QoS, TlsConfiguration, Transport};
+use rumqttc::tokio_rustls::rustls::{ClientConfig, RootCertStore};
+#[cfg(feature = "tokio")]
+use rumqttc::AsyncClient;
+
+use log::{info, warn};
+
+use crate::config::MqttConfig;
+
+const MQTT_QUEUE_CAP: usize = 64;
+const MQTT_RETRY_DELAY: Duration = Duration::from_secs(5);
+// while on a fallback broker, how often the primary one is checked for return
+const PRIMARY_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+const PRIMARY_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// bridge availability, retained on <base>/status ("offline" is also the last will)
+pub const STATUS_ONLINE: &str = "online";
+pub const STATUS_DEGRADED: &str = "degraded";
+pub const STATUS_OFFLINE: &str = "offline";
+
+
+// brokers of mqtt config (host / port first, then brokers) and the one in use,
+// switched by the event loop on connection errors
+struct Failover {
+    options: Vec<MqttOptions>,
+    active: usize,
+    probed: Instant,
+}
+
+impl Failover {
+    fn new(cfg: &MqttConfig) -> io::Result<Failover> {
+        let options = std::iter::once((&cfg.host, cfg.port))
+            .chain(cfg.brokers.iter().map(|b| (&b.host, b.port)))
+            .map(|(host, port)| options(cfg, host, port))
+            .collect::<io::Result<_>>()?;
+        Ok(Failover { options, active: 0, probed: Instant::now() })
+    }
+
+    fn broker(&self) -> String {
+        let (host, port) = self.options[self.active].broker_address();
+        format!("{}:{}", host, port)
+    }
+
+    fn connected(&self) {
+        info!("Connected to MQTT broker {}", self.broker());
+    }
+
+    // next broker is tried on the following poll
+    fn failed(&mut self, event_loop: &mut EventLoop) {
+        if self.options.len() > 1 {
+            self.active = (self.active + 1) % self.options.len();
+            event_loop.mqtt_options = self.options[self.active].clone();
+            warn!("Failing over to MQTT broker {}", self.broker());
+        }
+    }
+
+    // primary broker address while on a fallback one, once per PRIMARY_PROBE_INTERVAL
+    fn primary_probe_due(&mut self) -> Option<(String, u16)> {
+        if self.active == 0 || self.probed.elapsed() < PRIMARY_PROBE_INTERVAL {
+            return None;
+        }
+        self.probed = Instant::now();
+        Some(self.options[0].broker_address())
+    }
+
+    fn switch_to_primary(&mut self, event_loop: &mut EventLoop) {
+        self.active = 0;
+        event_loop.mqtt_options = self.options[0].clone();
+        // queued publishes are kept and sent after reconnect
+        event_loop.clean();
+        info!("MQTT broker {} is back, switching to it", self.broker());
+    }
+
+    // back to primary broker as soon as it accepts connections again,
+    // blocks the event loop thread while resolving and connecting
+    fn probe_primary(&mut self, event_loop: &mut EventLoop) {
+        if let Some((host, port)) = self.primary_probe_due() {
+            let reachable = (host.as_str(), port).to_socket_addrs().ok()
+                .and_then(|mut addrs| addrs.next())
+                .is_some_and(|addr| TcpStream::connect_timeout(&addr, PRIMARY_PROBE_TIMEOUT).is_ok());
+            if reachable {
+                self.switch_to_primary(event_loop);
+            }
+        }
+    }
+
+    // probe_primary without blocking the runtime, name resolution counts into
+    // PRIMARY_PROBE_TIMEOUT too
+    #[cfg(feature = "tokio")]
+    async fn probe_primary_async(&mut self, event_loop: &mut EventLoop) {
+        if let Some((host, port)) = self.primary_probe_due() {
+            let connect = tokio::net::TcpStream::connect((host.as_str(), port));
+            if let Ok(Ok(_)) = tokio::time::timeout(PRIMARY_PROBE_TIMEOUT, connect).await {
+                self.switch_to_primary(event_loop);
+            }
+        }
+    }
+}
+
+// sync client driven by own thread, or async one driven by a tokio task
+#[derive(Clone)]
+enum MqttClient {
+    Sync(Client),
+    #[cfg(feature = "tokio")]
+    Async(AsyncClient),
+}
+
+impl MqttClient {
+    fn try_publish(&self, topic: &str, qos: QoS, retain: bool, payload: impl Into<Vec<u8>>) -> Result<(), ClientError> {
+        match self {
+            MqttClient::Sync(client) => client.try_publish(topic, qos, retain, payload),
+            #[cfg(feature = "tokio")]
+            MqttClient::Async(client) => client.try_publish(topic, qos, retain, payload),
+        }
+    }
+
+    fn try_subscribe(&self, topic: &str, qos: QoS) -> Result<(), ClientError> {
+        match self {
+            MqttClient::Sync(client) => client.try_subscribe(topic, qos),
+            #[cfg(feature = "tokio")]
+            MqttClient::Async(client) => client.try_subscribe(topic, qos),
+        }
+    }
+
+    fn disconnect(&self) -> Result<(), ClientError> {
+        match self {
+            MqttClient::Sync(client) => client.disconnect(),
+            #[cfg(feature = "tokio")]
+            MqttClient::Async(client) => client.try_disconnect(),
+        }
+    }
+}
+
+// received publish (topic, payload) on a subscribed topic
+pub type Incoming = (String, Vec<u8>);
+
+// what the event loop needs to restore session state on (re)connect,
+// and where it passes received publishes to
+struct Session {
+    client: MqttClient,
+    status_topic: String,
+    status: Arc<Mutex<&'static str>>,
+    subscriptions: Arc<Mutex<Vec<String>>>,
+    incoming: Arc<Mutex<Option<mpsc::Sender<Incoming>>>>,
+}
+
+impl Session {
+    fn new(client: &MqttClient, cfg: &MqttConfig) -> Session {
+        Session {
+            client: client.clone(),
+            status_topic: format!("{}/status", cfg.topic),
+            status: Arc::new(Mutex::new(STATUS_ONLINE)),
+            subscriptions: Arc::new(Mutex::new(Vec::new())),
+            incoming: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // broker has cleared our status with last will if we were gone, and
+    // forgotten subscriptions with clean session
+    fn connected(&self) {
+        publish_status(&self.client, &self.status_topic, &self.status);
+        for topic in self.subscriptions.lock().unwrap().iter() {
+            if let Err(e) = self.client.try_subscribe(topic, QoS::AtLeastOnce) {
+                warn!("MQTT subscribe to {} dropped: {}", topic, e);
+            }
+        }
+    }
+
+    fn received(&self, publish: &Publish) {
+        if let Some(incoming) = &*self.incoming.lock().unwrap() {
+            let _ = incoming.send((publish.topic.clone(), publish.payload.to_vec()));
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MqttPublisher {
+    client: MqttClient,
+    base_topic: String,
+    stopping: Arc<AtomicBool>,
+    event_loop: Arc<Mutex<Option<JoinHandle<()>>>>,
+    // last status set, republished on every (re)connect
+    status: Arc<Mutex<&'static str>>,
+    // mqtt.qos, used unless publish_qos is given one
+    qos: u8,
+    // topics of subscribe, renewed on every (re)connect
+    subscriptions: Arc<Mutex<Vec<String>>>,
+    incoming: Arc<Mutex<Option<mpsc::Sender<Incoming>>>>,
+}
+
+impl MqttPublisher {
+    // connects to broker and spawns a thread driving the connection event loop
+    // rumqttc reconnects by itself on next poll, so errors are only logged
+    // fails only when configured certificate files can't be read
+    pub fn connect(cfg: &MqttConfig) -> io::Result<MqttPublisher> {
+        let mut failover = Failover::new(cfg)?;
+        let (client, mut connection) = Client::new(failover.options[0].clone(), MQTT_QUEUE_CAP);
+        let client = MqttClient::Sync(client);
+        let stopping = Arc::new(AtomicBool::new(false));
+        let stopping_clone = stopping.clone();
+        let session = Session::new(&client, cfg);
+        let (status, subscriptions, incoming) = (session.status.clone(), session.subscriptions.clone(), session.incoming.clone());
+        let event_loop = thread::spawn(move || {
+            while let Ok(notification) = connection.recv() {
+                match notification {
+                    // everything queued before disconnect has been sent
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        failover.connected();
+                        session.connected();
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => session.received(&publish),
+                    // keep alive pings make sure we get here regularly
+                    Ok(_) => failover.probe_primary(&mut connection.eventloop),
+                    Err(_) if stopping_clone.load(Ordering::Relaxed) => break,
+                    Err(e) => {
+                        warn!("MQTT connection error: {}", e);
+                        failover.failed(&mut connection.eventloop);
+                        thread::sleep(MQTT_RETRY_DELAY);
+                    }
+                }
+            }
+        });
+
+        Ok(MqttPublisher {
+            client,
+            base_topic: cfg.topic.clone(),
+            stopping,
+            event_loop: Arc::new(Mutex::new(Some(event_loop))),
+            status,
+            qos: cfg.qos,
+            subscriptions,
+            incoming,
+        })
+    }
+
+    // like connect, but the event loop is a task of the current tokio runtime
+    // (must be called from within one), disconnect doesn't wait for it to end
+    #[cfg(feature = "tokio")]
+    pub fn connect_async(cfg: &MqttConfig) -> io::Result<MqttPublisher> {
+        let mut failover = Failover::new(cfg)?;
+        let (client, mut event_loop) = AsyncClient::new(failover.options[0].clone(), MQTT_QUEUE_CAP);
+        let client = MqttClient::Async(client);
+        let stopping = Arc::new(AtomicBool::new(false));
+        let stopping_clone = stopping.clone();
+        let session = Session::new(&client, cfg);
+        let (status, subscriptions, incoming) = (session.status.clone(), session.subscriptions.clone(), session.incoming.clone());
+        tokio::spawn(async move {
+            loop {
+                match event_loop.poll().await {
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        failover.connected();
+                        session.connected();
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => session.received(&publish),
+                    Ok(_) => failover.probe_primary_async(&mut event_loop).await,
+                    Err(_) if stopping_clone.load(Ordering::Relaxed) => break,
+                    Err(e) => {
+                        warn!("MQTT connection error: {}", e);
+                        failover.failed(&mut event_loop);
+                        tokio::time::sleep(MQTT_RETRY_DELAY).await;
+                    }
+                }
+            }
+        });
+
+        Ok(MqttPublisher {
+            client,
+            base_topic: cfg.topic.clone(),
+            stopping,
+            event_loop: Arc::new(Mutex::new(None)),
+            status,
+            qos: cfg.qos,
+            subscriptions,
+            incoming,
+        })
+    }
+
+    // flushes queued publishes, disconnects from broker and waits for event loop to end
+    pub fn disconnect(&self) {
+        // last will is not sent on clean disconnect
+        self.set_status(STATUS_OFFLINE);
+        self.stopping.store(true, Ordering::Relaxed);
+        if let Err(e) = self.client.disconnect() {
+            warn!("MQTT disconnect failed: {}", e);
+        }
+        if let Some(event_loop) = self.event_loop.lock().unwrap().take() {
+            let _ = event_loop.join();
+        }
+    }
+
+    // publishes bridge status to <base>/status, retained
+    pub fn set_status(&self, status: &'static str) {
+        *self.status.lock().unwrap() = status;
+        let topic = format!("{}/status", self.base_topic);
+        if let Err(e) = self.client.try_publish(&topic, QoS::AtLeastOnce, true, status) {
+            warn!("MQTT publish to {} dropped: {}", topic, e);
+        }
+    }
+
+    // subscribes topics (QoS 1), publishes received on them are passed to
+    // the returned receiver (of the latest subscribe call)
+    pub fn subscribe(&self, topics: Vec<String>) -> mpsc::Receiver<Incoming> {
+        let (tx, rx) = mpsc::channel();
+        *self.incoming.lock().unwrap() = Some(tx);
+        for topic in &topics {
+            if let Err(e) = self.client.try_subscribe(topic, QoS::AtLeastOnce) {
+                warn!("MQTT subscribe to {} dropped: {}", topic, e);
+            }
+        }
+        self.subscriptions.lock().unwrap().extend(topics);
+        rx
+    }
+
+    pub fn base_topic(&self) -> &str {
+        &self.base_topic
+    }
+
+    // publish with mqtt.qos; when the outgoing queue is full (broker unreachable)
+    // the message is dropped instead of blocking the bus receiver
+    pub fn publish(&self, topic: &str, payload: String) {
+        self.publish_qos(topic, payload, self.qos, false);
+    }
+
+    pub fn publish_retained(&self, topic: &str, payload: String) {
+        self.publish_qos(topic, payload, self.qos, true);
+    }
+
+    // qos 0..=2, higher values are treated as 2
+    pub fn publish_qos(&self, topic: &str, payload: String, qos: u8, retain: bool) {
+        if let Err(e) = self.client.try_publish(topic, qos_level(qos), retain, payload) {
+            warn!("MQTT publish to {} dropped: {}", topic, e);
+        }
+    }
+
+    pub fn qos(&self) -> u8 {
+        self.qos
+    }
+}
+
+fn options(cfg: &MqttConfig, host: &str, port: u16) -> io::Result<MqttOptions> {
+    let mut opts = MqttOptions::new(&cfg.client_id, host, port);
+    opts.set_keep_alive(Duration::from_secs(30));
+    opts.set_clean_session(cfg.clean_session);
+    if !cfg.user.is_empty() {
+        opts.set_credentials(&cfg.user, &cfg.pass);
+    }
+    if cfg.tls {
+        opts.set_transport(Transport::tls_with_config(tls_config(cfg)?));
+    }
+    let status_topic = format!("{}/status", cfg.topic);
+    opts.set_last_will(LastWill::new(&status_topic, STATUS_OFFLINE, QoS::AtLeastOnce, true));
+    Ok(opts)
+}
+
+// republishes last status set, on (re)connect
+fn publish_status(client: &MqttClient, topic: &str, status: &Mutex<&'static str>) {
+    let status = *status.lock().unwrap();
+    if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, true, status) {
+        warn!("MQTT status publish dropped: {}", e);
+    }
+}
+
+fn qos_level(qos: u8) -> QoS {
+    match qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
+// without ca_cert the server is verified against the system root store,
+// client_cert and client_key come together (checked by Config::load)
+fn tls_config(cfg: &MqttConfig) -> io::Result<TlsConfiguration> {
+    let read = |path: &String| fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)));
+    let client_auth = match (&cfg.client_cert, &cfg.client_key) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        _ => None,
+    };
+    match (&cfg.ca_cert, client_auth) {
+        (Some(ca), client_auth) => Ok(TlsConfiguration::Simple { ca: read(ca)?, alpn: None, client_auth }),
+        (None, None) => Ok(TlsConfiguration::default()),
+        (None, Some((cert, key))) => system_roots_client_auth(&cert, &key),
+    }
+}
+
+// system root store with client certificate, TlsConfiguration::Simple
+// only takes the server CA from a file
+fn system_roots_client_auth(cert: &[u8], key: &[u8]) -> io::Result<TlsConfiguration> {
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(rustls_native_certs::load_native_certs()?);
+    let certs = rustls_pemfile::certs(&mut &cert[..]).collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut &key[..])?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "client_key: no private key found"))?;
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("client_cert: {}", e)))?;
+    Ok(TlsConfiguration::from(config))
+}
+
+// builds topic segment from free-form names (eg. message comment)
+pub fn topic_segment(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| match c {
+            ' ' | '/' | '+' | '#' => '_',
+            _ => c,
+        })
+        .collect()
+}
@@ -0,0 +1,97 @@
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::source::EbusStream;
+use crate::EbusParser;
+
+
+// why the stream is given up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interruption {
+    // read failed or stream ended
+    Lost,
+    // no complete frame for this long (watchdog)
+    Stalled(Duration),
+}
+
+type TickCallback = dyn FnMut(&EbusParser);
+type InterruptedCallback = dyn FnMut(Interruption) -> Option<Box<dyn EbusStream>>;
+
+// receive loop feeding interface stream into parser, chained:
+// Receiver::new(1024).watchdog(timeout).on_tick(..).on_interrupted(..).run(stream, &mut parser, &running)
+pub struct Receiver {
+    read_buffer_size: usize,
+    watchdog: Option<Duration>,
+    tick: Option<Box<TickCallback>>,
+    interrupted: Option<Box<InterruptedCallback>>,
+}
+
+impl Receiver {
+    pub fn new(read_buffer_size: usize) -> Receiver {
+        Receiver { read_buffer_size, watchdog: None, tick: None, interrupted: None }
+    }
+
+    // stream counts as stalled after timeout without a complete frame
+    pub fn watchdog(mut self, timeout: Option<Duration>) -> Self {
+        self.watchdog = timeout;
+        self
+    }
+
+    // called after every read, read timeouts included
+    pub fn on_tick(mut self, cb: impl FnMut(&EbusParser) + 'static) -> Self {
+        self.tick = Some(Box::new(cb));
+        self
+    }
+
+    // called with the (already shut down) stream lost or stalled, returns the
+    // one to continue with; without it, or when it returns None, run ends
+    pub fn on_interrupted(mut self, cb: impl FnMut(Interruption) -> Option<Box<dyn EbusStream>> + 'static) -> Self {
+        self.interrupted = Some(Box::new(cb));
+        self
+    }
+
+    // reads until `running` is cleared or the stream is given up
+    pub fn run(mut self, mut stream: Box<dyn EbusStream>, parser: &mut EbusParser, running: &AtomicBool) {
+        let mut buffer = vec![0; self.read_buffer_size];
+        let mut watch_since = Instant::now();
+        while running.load(Ordering::Relaxed) {
+            let lost = match stream.read(&mut buffer) {
+                Ok(n) if n > 0 => {
+                    parser.feed(&buffer[0..n], n);
+                    false
+                }
+                // read timeout, nothing arrived - parse what's held back by flush
+                // threshold and check `running` again
+                Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => {
+                    parser.flush();
+                    false
+                }
+                Ok(_) | Err(_) => true
+            };
+            if let Some(tick) = &mut self.tick {
+                tick(parser);
+            }
+            // adapter can stop sending while the connection stays open
+            let idle = parser.last_frame().map_or(watch_since, |t| t.max(watch_since)).elapsed();
+            let interruption = if lost {
+                Interruption::Lost
+            } else if self.watchdog.is_some_and(|timeout| idle >= timeout) {
+                Interruption::Stalled(idle)
+            } else {
+                continue;
+            };
+            stream.shutdown();
+            match self.interrupted.as_mut().and_then(|cb| cb(interruption)) {
+                Some(s) => {
+                    stream = s;
+                    // drop any partially received frame
+                    parser.reset();
+                    watch_since = Instant::now();
+                }
+                None => return,
+            }
+        }
+        stream.shutdown();
+    }
+}
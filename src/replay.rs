@@ -0,0 +1,118 @@
+use std::fs;
+use std::io;
+
+use crate::ebus::parser::{EbusProtocol, ACK, BROADCAST, SYN};
+use crate::EbusParser;
+
+// same chunk size as TCP receive buffer, to behave like a live connection
+const REPLAY_CHUNK: usize = 1024;
+
+
+// bytes to be replayed and the protocol they are in, None for the one
+// configured for the interface
+pub struct Capture {
+    pub bytes: Vec<u8>,
+    pub protocol: Option<EbusProtocol>,
+}
+
+// loads capture file, one of:
+// - capture log (capture_file) lines "<timestamp> <request> [<response>]",
+//   framed again with SYN / ACK into raw bus bytes
+// - text with hex bytes separated by whitespace (lines starting with # are comments)
+// - binary dump of the interface stream
+pub fn read_capture(filename: &str) -> io::Result<Capture> {
+    let content = fs::read(filename)?;
+    let text = std::str::from_utf8(&content).ok();
+    if let Some(bytes) = text.and_then(parse_capture_log) {
+        return Ok(Capture { bytes, protocol: Some(EbusProtocol::Raw) });
+    }
+    match text.and_then(parse_hex_text) {
+        Some(bytes) => Ok(Capture { bytes, protocol: None }),
+        None => Ok(Capture { bytes: content, protocol: None })
+    }
+}
+
+fn parse_hex_text(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for line in text.lines().map(str::trim).filter(|l| !l.starts_with('#')) {
+        for token in line.split_whitespace() {
+            bytes.extend(hex::decode(token).ok()?);
+        }
+    }
+    Some(bytes)
+}
+
+// None when text is no capture log (first token of a line no timestamp)
+fn parse_capture_log(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![SYN];
+    let mut lines = 0;
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let mut tokens = line.split_whitespace();
+        humantime::parse_rfc3339_weak(tokens.next()?).ok()?;
+        let req = hex::decode(tokens.next()?).ok()?;
+        let resp = tokens.next().map(hex::decode).transpose().ok()?;
+        bytes.extend(&req);
+        // broadcasts are never ACKed
+        if req.get(1) != Some(&BROADCAST) {
+            bytes.push(ACK);
+        }
+        if let Some(resp) = resp {
+            bytes.extend(resp);
+            bytes.push(ACK);
+        }
+        bytes.push(SYN);
+        lines += 1;
+    }
+    (lines > 0).then_some(bytes)
+}
+
+// feeds captured bytes through parser exactly as receive loop does
+pub fn replay(bytes: &[u8], parser: &mut EbusParser) {
+    for chunk in bytes.chunks(REPLAY_CHUNK) {
+        parser.feed(chunk, chunk.len());
+    }
+    parser.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::ebus::capture::CaptureLog;
+    use crate::{EbusParserBuilder, EbusRequest, EbusResponse};
+
+    #[test]
+    fn capture_log_replays_its_frames() {
+        let filename = std::env::temp_dir().join(format!("ebus_mqtt_capture_{}.log", std::process::id()));
+        let filename = filename.to_str().unwrap();
+        let _ = fs::remove_file(filename);
+        // master-slave with escaped data, master-master and broadcast
+        let frames = [
+            (EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0xAA, 0xA9]), Some(EbusResponse::new(&[0x50, 0x03]))),
+            (EbusRequest::new(0x10, 0x03, 0xB505, &[0x2D, 0x00]), None),
+            (EbusRequest::new(0x10, BROADCAST, 0x0700, &[0x80, 0x0A]), None),
+        ];
+        let mut capture = CaptureLog::open(filename, 1024).unwrap();
+        for (req, resp) in &frames {
+            capture.write_frame(req, resp.as_ref()).unwrap();
+        }
+
+        let capture = read_capture(filename).unwrap();
+        fs::remove_file(filename).unwrap();
+        assert_eq!(capture.protocol, Some(EbusProtocol::Raw));
+        let replayed = Arc::new(Mutex::new(Vec::new()));
+        let sink = replayed.clone();
+        let mut parser = EbusParserBuilder::new().protocol(EbusProtocol::Raw)
+            .build(move |req, resp| sink.lock().unwrap().push((req.to_bytes(), resp.map(|r| r.to_bytes()))));
+        replay(&capture.bytes, &mut parser);
+        let expected: Vec<_> = frames.iter().map(|(req, resp)| (req.to_bytes(), resp.as_ref().map(|r| r.to_bytes()))).collect();
+        assert_eq!(*replayed.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn hex_text_is_taken_as_interface_stream() {
+        assert_eq!(parse_capture_log("AA 10 08"), None);
+        assert_eq!(parse_hex_text("# comment\nAA 1008\n"), Some(vec![0xAA, 0x10, 0x08]));
+    }
+}
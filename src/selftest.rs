@@ -0,0 +1,113 @@
+use std::sync::{Arc, Mutex};
+
+use crate::ebus::parser::{encode_enhproto_tuple, EbusProtocol, EbusResponse, ACK, SYN};
+use crate::mapper::{self, MapperOptions, OutputMode};
+use crate::{replay, EbusParserBuilder, EbusRequest, Mapper};
+
+// bundled demo definitions, the frames below are built to match them
+pub const DEMO_DEFINITIONS: &str = include_str!("../demo.json");
+// base topic shown for publishes which would have been made
+const SELFTEST_BASE_TOPIC: &str = "ebus";
+// RECEIVED of enhanced protocol
+const ENH_RECEIVED: u8 = 0x1;
+
+
+// synthetic telegram and what publishing it has to produce
+struct Case {
+    req: EbusRequest,
+    resp: Option<EbusResponse>,
+    circuit: &'static str,
+    message: &'static str,
+    fields: serde_json::Value,
+}
+
+fn cases() -> Vec<Case> {
+    vec![
+        // DATA2c 0x0350 = 53.0, state 1 named by values map
+        Case {
+            req: EbusRequest::new(0x10, 0x08, 0xB509, &[0x0D, 0x29, 0x00]),
+            resp: Some(EbusResponse::new(&[0x50, 0x03, 0x01])),
+            circuit: "heater",
+            message: "flow",
+            fields: serde_json::json!({ "flow_temp": 53.0, "state": "heating" }),
+        },
+        // repeated field, times 06:30:00 and 22:00:00 at offsets 1 and 4
+        Case {
+            req: EbusRequest::new(0x10, 0x03, 0xB515, &[0x01, 0x00, 0x30, 0x06, 0x00, 0x00, 0x22]),
+            resp: None,
+            circuit: "heater",
+            message: "heating schedule",
+            fields: serde_json::json!({ "day": 1, "switch_times": ["06:30:00", "22:00:00"] }),
+        },
+    ]
+}
+
+// interface stream of all cases as ebusd adapter sends it (enhanced protocol)
+fn stream(cases: &[Case]) -> Vec<u8> {
+    let mut bus = vec![SYN];
+    for case in cases {
+        bus.extend(case.req.raw());
+        if case.req.dest() != 0xFE {
+            bus.push(ACK);
+        }
+        if let Some(resp) = &case.resp {
+            bus.extend(resp.raw());
+            bus.push(ACK);
+        }
+        bus.push(SYN);
+    }
+    let mut bytes = Vec::new();
+    for b in bus {
+        if b < 0x80 {
+            bytes.push(b);
+        } else {
+            let (b1, b2) = encode_enhproto_tuple(ENH_RECEIVED, b);
+            bytes.extend([b1, b2]);
+        }
+    }
+    bytes
+}
+
+// feeds synthetic frames through parser and mapper, prints each publish which
+// would have been made, true when all of them came out as expected
+pub fn run() -> bool {
+    let defs: serde_json::Value = serde_json::from_str(DEMO_DEFINITIONS).unwrap();
+    let errors = mapper::validate_definitions(&defs);
+    if !errors.is_empty() {
+        errors.iter().for_each(|e| println!("FAIL demo definitions: {}", e));
+        return false;
+    }
+    let options = MapperOptions { output: OutputMode::None, ..Default::default() };
+    let mapper = Mapper::new(vec![defs.clone()], None, options);
+
+    let decoded = Arc::new(Mutex::new(Vec::new()));
+    let decoded_clone = decoded.clone();
+    let mut parser = EbusParserBuilder::new()
+        .protocol(EbusProtocol::Enhanced)
+        .build(move |req, resp| decoded_clone.lock().unwrap().push(mapper.decode(req, resp)));
+    let cases = cases();
+    replay::replay(&stream(&cases), &mut parser);
+
+    let decoded = decoded.lock().unwrap();
+    let mut ok = decoded.len() == cases.len();
+    if !ok {
+        println!("FAIL {} frame(s) sent, {} received", cases.len(), decoded.len());
+    }
+    for (case, result) in cases.iter().zip(decoded.iter()) {
+        let expected = serde_json::json!([{ "circuit": case.circuit, "message": case.message, "fields": case.fields }]);
+        let msg = defs["circuits"].as_array().unwrap().iter()
+            .filter(|c| c["name"] == case.circuit)
+            .flat_map(|c| c["messages"].as_array().unwrap())
+            .find(|m| m["comment"] == case.message)
+            .unwrap();
+        let topic = mapper::topic(SELFTEST_BASE_TOPIC, case.circuit, msg, false);
+        if result.as_ref() == Some(&expected) {
+            println!("OK   {} {}", topic, case.fields);
+        } else {
+            println!("FAIL {} expected {}, decoded {}", topic, case.fields,
+                result.as_ref().map_or("nothing".to_string(), |r| r.to_string()));
+            ok = false;
+        }
+    }
+    ok
+}
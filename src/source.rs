@@ -0,0 +1,128 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use serialport::SerialPort;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+#[cfg(feature = "tokio")]
+use crate::EbusParser;
+
+pub const DEFAULT_SERIAL_BAUD: u32 = 2400;
+// reads return periodically so the receiver can observe shutdown
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+
+// opened byte stream, closed explicitly on shutdown
+pub trait EbusStream: Read + Send {
+    fn shutdown(&mut self) {}
+
+    // independent handle for writing to the interface (see EbusSender)
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "stream is read-only"))
+    }
+}
+
+impl EbusStream for TcpStream {
+    fn shutdown(&mut self) {
+        let _ = TcpStream::shutdown(self, Shutdown::Both);
+    }
+
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+impl EbusStream for Box<dyn SerialPort> {
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+// canned interface bytes, for running the receiver without hardware or network,
+// reads return 0 once all bytes are consumed (same as a closed connection)
+impl<T: AsRef<[u8]> + Send> EbusStream for io::Cursor<T> {}
+
+
+// byte source the parser is fed from
+#[derive(Debug, Clone)]
+pub enum EbusSource {
+    Tcp(String),
+    Serial(String, u32),
+}
+
+impl EbusSource {
+    pub fn open(&self) -> io::Result<Box<dyn EbusStream>> {
+        match self {
+            EbusSource::Tcp(addr) => {
+                let stream = connect_tcp(addr)?;
+                stream.set_read_timeout(Some(READ_TIMEOUT))?;
+                Ok(Box::new(stream))
+            }
+            EbusSource::Serial(device, baud) => {
+                let port = serialport::new(device, *baud)
+                    .timeout(READ_TIMEOUT)
+                    .open()?;
+                Ok(Box::new(port))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl EbusSource {
+    // async counterpart of open, must be called from within a tokio runtime
+    pub async fn open_async(&self) -> io::Result<Box<dyn AsyncRead + Unpin + Send>> {
+        match self {
+            EbusSource::Tcp(addr) => Ok(Box::new(tokio::net::TcpStream::connect(addr).await?)),
+            EbusSource::Serial(device, baud) => {
+                let port = tokio_serial::SerialStream::open(&tokio_serial::new(device, *baud))?;
+                Ok(Box::new(port))
+            }
+        }
+    }
+}
+
+// feeds everything read from stream into parser until the stream ends (Ok) or fails,
+// parser callbacks run on the reading task
+#[cfg(feature = "tokio")]
+pub async fn read_async<R: AsyncRead + Unpin + ?Sized>(stream: &mut R, parser: &mut EbusParser, buffer_size: usize) -> io::Result<()> {
+    let mut buffer = vec![0; buffer_size];
+    loop {
+        let len = stream.read(&mut buffer).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        parser.feed(&buffer, len);
+    }
+}
+
+// resolves host (name, IPv4 or bracketed IPv6 literal) and tries each address in turn,
+// error lists every address attempted
+fn connect_tcp(addr: &str) -> io::Result<TcpStream> {
+    let addrs: Vec<_> = addr.to_socket_addrs()
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to resolve host: {}", e)))?
+        .collect();
+    let mut failures = Vec::new();
+    for a in &addrs {
+        match TcpStream::connect(a) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => failures.push(format!("{}: {}", a, e)),
+        }
+    }
+    if failures.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} resolved to no addresses", addr)));
+    }
+    Err(io::Error::new(io::ErrorKind::ConnectionRefused, failures.join(", ")))
+}
+
+impl Display for EbusSource {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            EbusSource::Tcp(addr) => write!(f, "tcp://{}", addr),
+            EbusSource::Serial(device, baud) => write!(f, "serial://{}@{}", device, baud)
+        }
+    }
+}